@@ -13,7 +13,10 @@ for n_nodes in [2] + list(range(2, 9)):
 
             leader = subprocess.run(["./target/release/eval", str(n_nodes), "0", str(n_tasks)], capture_output=True, text=True)
             for proc in followers:
-                proc.terminate()
+                try:
+                    proc.wait(timeout=10)
+                except subprocess.TimeoutExpired:
+                    proc.terminate()
             if leader.returncode == 0:
                 print(n_nodes, n_tasks, leader.stdout.strip())
                 break
@@ -28,7 +28,7 @@ async fn dfut_main(n_tasks: usize) -> () {
     let mut tasks = Vec::with_capacity(n_tasks);
     let start = Instant::now();
     for _ in 0..n_tasks {
-        tasks.push(IntoFuture::into_future(dfut::spawn(noop())))
+        tasks.push(IntoFuture::into_future(dfut::submit(noop()).await))
     }
     for fut in tasks.drain(..) {
         fut.await;
@@ -60,5 +60,5 @@ fn main() {
         );
     }
     let node = Node::new(id, config).unwrap();
-    node.start((id == 0).then(|| dfut_main(n_tasks)));
+    std::process::exit(node.start((id == 0).then_some(dfut_main(n_tasks))));
 }
@@ -39,5 +39,5 @@ fn main() {
     };
     let id = args().nth(1).unwrap().parse().unwrap();
     let node = Node::new(id, config).unwrap();
-    node.start((id == 0).then(dfut_main));
+    std::process::exit(node.start((id == 0).then(dfut_main)));
 }
@@ -28,7 +28,7 @@ dfut_procs! {
 
 #[requires(cpus(1))]
 async fn find_links(html: Html) -> Vec<String> {
-    const PATTERN: &'static str = "<a href=\"/wiki/";
+    const PATTERN: &str = "<a href=\"/wiki/";
     let res = cpus.run(move || {
         let mut res = Vec::new();
         let mut s = &html.0[..];
@@ -64,11 +64,11 @@ async fn dfut_main(start: String, end: String) -> () {
     loop {
         let mut set = JoinSet::new();
         for s in open_set.drain(..) {
-            set.spawn(async move {
+            set.spawn(dfut::in_context(async move {
                 let html = dfut::spawn(get_html(s.clone()));
                 let children: Vec<String> = dfut::spawn(find_links(html)).await;
                 (s, children)
-            });
+            }));
         }
         while let Some(res) = set.join_next().await {
             let (s, mut children) = res.unwrap();
@@ -104,5 +104,5 @@ fn main() {
     };
 
     let node = Node::new(id, config).unwrap();
-    node.start(main);
+    std::process::exit(node.start(main));
 }
@@ -40,5 +40,5 @@ fn main() {
         None
     };
     let node = Node::new(id, config).unwrap();
-    node.start(main);
+    std::process::exit(node.start(main));
 }
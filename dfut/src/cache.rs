@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::watch;
+
+use crate::types::{DFutId, NodeId};
+
+type Slot = Arc<watch::Sender<Option<Arc<[u8]>>>>;
+
+/// Values this node fetched from other nodes, kept until the node holding a
+/// value frees it or is lost, so tasks here needing the same one share a
+/// single fetch.
+pub struct Cache {
+    entries: Mutex<HashMap<DFutId, (NodeId, Slot)>>,
+}
+
+pub enum Lookup<'a> {
+    /// Another task fetched the value or is fetching it.
+    Shared(watch::Receiver<Option<Arc<[u8]>>>),
+    /// Nobody has fetched it yet. The caller should, and fill in the value.
+    Fetch(Fetch<'a>),
+}
+
+impl Cache {
+    pub fn new() -> Self {
+        Self {
+            entries: Mutex::default(),
+        }
+    }
+
+    /// Looks up the value of `id`, which `node` holds.
+    pub fn lookup(&self, id: DFutId, node: NodeId) -> Lookup<'_> {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some((_, slot)) = entries.get(&id) {
+            return Lookup::Shared(slot.subscribe());
+        }
+        let slot = Slot::default();
+        entries.insert(id, (node, slot.clone()));
+        Lookup::Fetch(Fetch {
+            cache: self,
+            id,
+            slot,
+        })
+    }
+
+    /// Forgets a value nothing needs any more.
+    pub fn remove(&self, id: DFutId) {
+        self.entries.lock().unwrap().remove(&id);
+    }
+
+    /// Forgets the values held by a lost node, which will never say they
+    /// were freed.
+    pub fn node_lost(&self, lost: NodeId) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (node, _)| *node != lost);
+    }
+}
+
+/// The one fetch of a value other tasks are waiting on. Dropping it without
+/// filling in the value lets them fetch it themselves.
+pub struct Fetch<'a> {
+    cache: &'a Cache,
+    id: DFutId,
+    slot: Slot,
+}
+
+impl Fetch<'_> {
+    pub fn fill(self, value: Arc<[u8]>) {
+        self.slot.send_replace(Some(value));
+    }
+}
+
+impl Drop for Fetch<'_> {
+    fn drop(&mut self) {
+        if self.slot.borrow().is_some() {
+            return;
+        }
+        let mut entries = self.cache.entries.lock().unwrap();
+        if entries
+            .get(&self.id)
+            .is_some_and(|(_, slot)| Arc::ptr_eq(slot, &self.slot))
+        {
+            entries.remove(&self.id);
+        }
+    }
+}
+
+/// Waits for a value another task is fetching, or `None` if it failed.
+pub async fn shared(mut rx: watch::Receiver<Option<Arc<[u8]>>>) -> Option<Arc<[u8]>> {
+    let value = rx.wait_for(Option::is_some).await.ok()?;
+    value.clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fetch(lookup: Lookup<'_>) -> Fetch<'_> {
+        match lookup {
+            Lookup::Fetch(fetch) => fetch,
+            Lookup::Shared(_) => panic!("already fetched"),
+        }
+    }
+
+    fn shares(lookup: Lookup<'_>) -> watch::Receiver<Option<Arc<[u8]>>> {
+        match lookup {
+            Lookup::Shared(rx) => rx,
+            Lookup::Fetch(_) => panic!("not fetched"),
+        }
+    }
+
+    #[tokio::test]
+    async fn lookups_share_one_fetch() {
+        let cache = Cache::new();
+        let id = DFutId::new_v4();
+        let first = fetch(cache.lookup(id, 1));
+        let waiting = shares(cache.lookup(id, 1));
+        first.fill(Arc::from([1, 2, 3]));
+        assert_eq!(shared(waiting).await.as_deref(), Some(&[1, 2, 3][..]));
+        // Later ones get the value without fetching it again.
+        let later = shares(cache.lookup(id, 1));
+        assert_eq!(shared(later).await.as_deref(), Some(&[1, 2, 3][..]));
+
+        // A fetch that fails lets the next task try.
+        let other = DFutId::new_v4();
+        let failed = fetch(cache.lookup(other, 1));
+        let waiting = shares(cache.lookup(other, 1));
+        drop(failed);
+        assert_eq!(shared(waiting).await, None);
+        fetch(cache.lookup(other, 1));
+    }
+
+    #[test]
+    fn values_are_forgotten_once_freed_or_lost() {
+        let cache = Cache::new();
+        let [freed, lost, kept] = [(); 3].map(|_| DFutId::new_v4());
+        for (id, node) in [(freed, 1), (lost, 2), (kept, 1)] {
+            fetch(cache.lookup(id, node)).fill(Arc::from([0]));
+        }
+        cache.remove(freed);
+        cache.node_lost(2);
+        fetch(cache.lookup(freed, 1));
+        fetch(cache.lookup(lost, 2));
+        shares(cache.lookup(kept, 1));
+    }
+}
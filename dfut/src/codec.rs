@@ -0,0 +1,51 @@
+use std::io::Write;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// How a node encodes commands and values, on the wire and in its spill
+/// directory. Every node in a cluster must use the same one. Sessions with
+/// nodes using another are refused.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Codec {
+    /// Self-describing, so it works with any value type.
+    #[default]
+    Cbor,
+    /// Smaller and faster to encode, particularly for numeric data. Types
+    /// that can only be read from a self-describing format, like untagged
+    /// enums, can't be used as arguments or results.
+    Bincode,
+    /// Like bincode, but with variable-length integers, so payloads of
+    /// mostly small numbers are smaller still.
+    Postcard,
+}
+
+impl Codec {
+    pub(crate) fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, String> {
+        let mut buf = Vec::new();
+        self.encode_into(&mut buf, value)?;
+        Ok(buf)
+    }
+
+    pub(crate) fn encode_into<T: Serialize>(
+        self,
+        writer: impl Write,
+        value: &T,
+    ) -> Result<(), String> {
+        match self {
+            Self::Cbor => serde_cbor::to_writer(writer, value).map_err(|e| e.to_string()),
+            Self::Bincode => bincode::serialize_into(writer, value).map_err(|e| e.to_string()),
+            Self::Postcard => postcard::to_io(value, writer)
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+        }
+    }
+
+    pub(crate) fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            Self::Cbor => serde_cbor::from_slice(bytes).map_err(|e| e.to_string()),
+            Self::Bincode => bincode::deserialize(bytes).map_err(|e| e.to_string()),
+            Self::Postcard => postcard::from_bytes(bytes).map_err(|e| e.to_string()),
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use std::io::Read;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::DFutError;
+use crate::stats::CompressionStats;
+
+/// How a node compresses the values it sends to peers that take compressed
+/// values. Any node can decompress either.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    /// Fast, for when the network isn't much slower than compressing.
+    Lz4,
+    /// Slower, but makes values smaller, text in particular.
+    Zstd,
+}
+
+impl Compression {
+    fn compress(self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        match self {
+            Self::Lz4 => Ok(lz4_flex::compress_prepend_size(bytes)),
+            Self::Zstd => zstd::bulk::compress(bytes, zstd::DEFAULT_COMPRESSION_LEVEL)
+                .map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Decompresses `bytes`, failing rather than making a value over
+    /// `limit`, whatever size the sender claims it is.
+    fn decompress(self, bytes: &[u8], limit: usize) -> Result<Vec<u8>, DFutError> {
+        let corrupt = |e: String| DFutError::Deserialize(format!("corrupt {self:?} data: {e}"));
+        let value = match self {
+            Self::Lz4 => {
+                let (len, rest) = lz4_flex::block::uncompressed_size(bytes)
+                    .map_err(|e| corrupt(e.to_string()))?;
+                if len > limit {
+                    return Err(DFutError::TooLarge(limit));
+                }
+                lz4_flex::decompress(rest, len).map_err(|e| corrupt(e.to_string()))?
+            }
+            Self::Zstd => {
+                // Reads one byte past the limit, to tell if it is over.
+                let mut value = Vec::new();
+                zstd::Decoder::new(bytes)
+                    .and_then(|decoder| decoder.take(limit as u64 + 1).read_to_end(&mut value))
+                    .map_err(|e| corrupt(e.to_string()))?;
+                value
+            }
+        };
+        if value.len() > limit {
+            return Err(DFutError::TooLarge(limit));
+        }
+        Ok(value)
+    }
+}
+
+/// Compresses the values a node sends and decompresses the ones it gets,
+/// keeping count of how well that went.
+pub(crate) struct Compressor {
+    /// What values are compressed with, and the smallest size worth it.
+    config: Option<(Compression, usize)>,
+    stats: Mutex<CompressionStats>,
+}
+
+impl Compressor {
+    pub fn new(config: Option<(Compression, usize)>) -> Self {
+        Self {
+            config,
+            stats: Mutex::default(),
+        }
+    }
+
+    /// How this node compresses values, if it does. Nodes that do take
+    /// compressed values from their peers.
+    pub fn compression(&self) -> Option<Compression> {
+        self.config.map(|(compression, _)| compression)
+    }
+
+    /// Compresses `bytes` if they are large enough, returning what to send
+    /// and how it was compressed. Values compressing doesn't make smaller are
+    /// sent as they are.
+    pub fn compress(&self, bytes: Box<[u8]>) -> (Box<[u8]>, Option<Compression>) {
+        let Some((compression, threshold)) = self.config else {
+            return (bytes, None);
+        };
+        if bytes.len() < threshold {
+            return (bytes, None);
+        }
+        let start = Instant::now();
+        let compressed = compression
+            .compress(&bytes)
+            .ok()
+            .filter(|compressed| compressed.len() < bytes.len());
+        let mut stats = self.stats.lock().unwrap();
+        stats.compress_time += start.elapsed();
+        stats.compressed += 1;
+        stats.original_bytes += bytes.len() as u64;
+        match compressed {
+            Some(compressed) => {
+                stats.compressed_bytes += compressed.len() as u64;
+                (compressed.into_boxed_slice(), Some(compression))
+            }
+            None => {
+                stats.compressed_bytes += bytes.len() as u64;
+                (bytes, None)
+            }
+        }
+    }
+
+    /// Decompresses a value from a peer, which fails if it is over `limit`.
+    pub fn decompress(
+        &self,
+        compression: Compression,
+        bytes: &[u8],
+        limit: usize,
+    ) -> Result<Box<[u8]>, DFutError> {
+        let start = Instant::now();
+        let res = compression.decompress(bytes, limit);
+        let mut stats = self.stats.lock().unwrap();
+        stats.decompress_time += start.elapsed();
+        stats.decompressed += 1;
+        res.map(Vec::into_boxed_slice)
+    }
+
+    pub fn stats(&self) -> CompressionStats {
+        *self.stats.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes no compression makes smaller.
+    fn noise(len: usize) -> Box<[u8]> {
+        let mut x = 0x2545_f491_4f6c_dd1du64;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn values_round_trip() {
+        for compression in [Compression::Lz4, Compression::Zstd] {
+            let compressor = Compressor::new(Some((compression, 16)));
+            let value: Box<[u8]> = "abc".repeat(1000).into_bytes().into();
+            let (bytes, used) = compressor.compress(value.clone());
+            assert_eq!(used, Some(compression));
+            assert!(bytes.len() < value.len());
+            let limit = value.len();
+            assert_eq!(
+                compressor.decompress(compression, &bytes, limit).unwrap(),
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn values_over_the_limit_fail() {
+        for compression in [Compression::Lz4, Compression::Zstd] {
+            let compressor = Compressor::new(Some((compression, 16)));
+            let (bytes, _) = compressor.compress(vec![0; 1000].into());
+            let res = compressor.decompress(compression, &bytes, 999);
+            assert!(matches!(res, Err(DFutError::TooLarge(999))));
+        }
+        // Refused from the size it claims, before making room for it.
+        let mut bytes = u32::MAX.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&[0; 8]);
+        let compressor = Compressor::new(None);
+        let res = compressor.decompress(Compression::Lz4, &bytes, 1 << 20);
+        assert!(matches!(res, Err(DFutError::TooLarge(limit)) if limit == 1 << 20));
+    }
+
+    #[test]
+    fn stats_count_what_was_compressed() {
+        let compressor = Compressor::new(Some((Compression::Lz4, 100)));
+        // Too small to bother with.
+        assert_eq!(compressor.compress(vec![0; 99].into()).1, None);
+        let (bytes, used) = compressor.compress(vec![0; 1000].into());
+        assert_eq!(used, Some(Compression::Lz4));
+        // Sent unchanged, as compressing it doesn't help.
+        assert_eq!(compressor.compress(noise(1000)).1, None);
+        compressor
+            .decompress(Compression::Lz4, &bytes, 1000)
+            .unwrap();
+        let stats = compressor.stats();
+        assert_eq!(stats.compressed, 2);
+        assert_eq!(stats.original_bytes, 2000);
+        assert_eq!(stats.compressed_bytes, bytes.len() as u64 + 1000);
+        assert_eq!(stats.decompressed, 1);
+    }
+}
@@ -1,86 +1,760 @@
-use serde::de::DeserializeOwned;
-use std::any::TypeId;
-use std::collections::HashMap;
-use std::future::Future;
+use std::collections::{HashMap, VecDeque};
+use std::future::{self, Future};
 use std::io::{self, ErrorKind};
-use std::pin::Pin;
+use std::mem;
+use std::net::SocketAddr;
+use std::pin::{pin, Pin};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use std::time::Duration;
+
+use serde::de::IgnoredAny;
 use tokio::sync::mpsc::{self, UnboundedReceiver as Receiver, UnboundedSender as Sender};
-use tokio::sync::oneshot;
-use tokio::task::JoinSet;
+use tokio::sync::{oneshot, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant, MissedTickBehavior};
+use uuid::Uuid;
 
-use crate::dfut::{DFut, DFutCall, DFutData, DFutTrait, DFutValue};
-use crate::protocol::Command;
+use crate::compression::Compression;
+use crate::dfut::{DFutCall, DFutData, DFutTrait};
+use crate::error::DFutError;
+use crate::protocol::{Batch, Command, Envelope, Member, Payload};
 use crate::resource::Resources;
-use crate::types::{DFutId, InstanceId, NodeId, ResourceConfig, Value};
+use crate::scheduler::NodeInfo;
+use crate::stats::SessionStats;
+use crate::store::Object;
+use crate::transport::{FrameWriter, Stream};
+use crate::types::{DFutId, InstanceId, NodeId, ResourceConfig};
 use crate::Node;
 
 pub struct Connection<C: DFutTrait> {
     id: NodeId,
+    addr: SocketAddr,
     resources: ResourceConfig,
     session: Mutex<Option<Session<C>>>,
+    load: Mutex<Load<C>>,
+    link: Mutex<Link<C>>,
+    delivery: Arc<Mutex<Delivery>>,
+    backlog: Arc<Backlog>,
+    state: Arc<Mutex<NodeState>>,
+    changed: Notify,
+}
+
+/// Liveness of a connected node, as judged from its heartbeats.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeState {
+    /// Heard from within the last two heartbeat intervals.
+    Alive,
+    /// Missed heartbeats, disconnected, or not connected yet, but not yet for
+    /// the full timeout.
+    Suspect,
+    /// Timed out or never came back. No work is placed on dead nodes.
+    Dead,
+}
+
+/// How often sessions ping their peer and how long a silent peer is given
+/// before it is declared dead.
+#[derive(Clone, Copy)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+/// How many commands sessions gather into one frame, and how long a frame
+/// that isn't full waits for more before it is written.
+#[derive(Clone, Copy)]
+pub struct Batching {
+    pub max_commands: usize,
+    pub flush_interval: Duration,
+}
+
+/// Largest frame sessions read or write, and largest value put back
+/// together from chunks.
+#[derive(Clone, Copy)]
+pub struct Limits {
+    pub frame_len: usize,
+    pub value_len: usize,
+}
+
+/// Resources this node has reserved on the connected node for the tasks it
+/// dispatched there, and the tasks waiting for those resources to free up.
+///
+/// Only this node's own tasks are counted. Every node spawning calls keeps
+/// its own `Load` for each peer, so tasks spawned from different nodes can
+/// together be sent more than a node was configured with. The node admits
+/// them one at a time as its resources free up.
+struct Load<C> {
+    in_use: ResourceConfig,
+    running: HashMap<DFutId, Vec<(String, usize)>>,
+    queued: VecDeque<QueuedCall<C>>,
+}
+
+struct QueuedCall<C> {
+    id: DFutId,
+    call: C,
+    reqs: Vec<(String, usize)>,
+}
+
+impl<C> Load<C> {
+    fn reserve(&mut self, id: DFutId, reqs: Vec<(String, usize)>) {
+        for (res, amt) in reqs.iter() {
+            *self.in_use.entry(res.clone()).or_default() += amt;
+        }
+        self.running.insert(id, reqs);
+    }
+
+    fn release(&mut self, id: DFutId) {
+        for (res, amt) in self.running.remove(&id).into_iter().flatten() {
+            *self.in_use.get_mut(&res).unwrap() -= amt;
+        }
+    }
+}
+
+/// Calls submitted through a connection that haven't been sent or started
+/// yet, whether waiting for resources, to be written, or for the node to
+/// reconnect, so spawning can wait while there are too many.
+#[derive(Default)]
+struct Backlog {
+    calls: AtomicUsize,
+    /// Notified whenever either count goes down.
+    room: Notify,
+    /// Commands of any kind queued on the current session.
+    commands: AtomicUsize,
+}
+
+impl Backlog {
+    fn len(&self) -> usize {
+        self.calls.load(Ordering::Relaxed)
+    }
+
+    fn commands(&self) -> usize {
+        self.commands.load(Ordering::Relaxed)
+    }
+
+    async fn wait_for(&self, room: impl Fn(&Self) -> bool) {
+        loop {
+            let mut freed = pin!(self.room.notified());
+            freed.as_mut().enable();
+            if room(self) {
+                return;
+            }
+            freed.await;
+        }
+    }
+
+    fn add(&self) {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn remove(&self, calls: usize) {
+        if calls > 0 {
+            self.calls.fetch_sub(calls, Ordering::Relaxed);
+            self.room.notify_waiters();
+        }
+    }
+
+    fn queued(&self) {
+        self.commands.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn dequeued(&self) {
+        self.commands.fetch_sub(1, Ordering::Relaxed);
+        self.room.notify_waiters();
+    }
+
+    /// Removes `cmd` if it is a call, which is about to be sent or dropped.
+    fn sent<C>(&self, cmd: &Command<C>) {
+        if matches!(cmd, Command::Call { .. }) {
+            self.remove(1);
+        }
+    }
+}
+
+/// State that outlives any one session with the connected node, so a dropped
+/// session can be replaced without failing the requests made through it.
+struct Link<C> {
+    /// Bumped for every new session, so stale sessions can be told apart.
+    generation: u64,
+    /// Identifies the process on the other end. Changes when it restarts.
+    incarnation: Option<Uuid>,
+    /// Whether the current session's peer has been checked for a restart
+    /// and can be sent requests.
+    greeted: bool,
+    /// Whether values sent on the current session may be compressed.
+    compressed: bool,
+    /// Commands waiting for the next greeted session.
+    parked: Vec<Command<C>>,
+    requests: HashMap<InstanceId, oneshot::Sender<Payload>>,
+    /// Chunks received so far of values too large to send in one frame.
+    partial: HashMap<InstanceId, Vec<u8>>,
+    locates: HashMap<DFutId, Vec<oneshot::Sender<Option<NodeId>>>>,
+}
+
+impl<C> Link<C> {
+    /// Forgets everything sent to the connected node, failing any requests
+    /// still waiting on it.
+    fn clear(&mut self) {
+        self.parked.clear();
+        self.requests.clear();
+        self.partial.clear();
+        self.locates.clear();
+    }
+}
+
+/// Numbering of the commands exchanged with the connected node, shared by
+/// its sessions. Commands stay here until the node acknowledges them, so the
+/// ones lost with a dropped session are sent again on the next.
+#[derive(Default)]
+struct Delivery {
+    /// The session allowed to send commands. Older sessions hand theirs back.
+    generation: u64,
+    /// Incarnation of the node the commands were received from.
+    peer: Option<Uuid>,
+    /// Sequence number of the last command sent.
+    sent: u64,
+    /// Commands sent but not acknowledged yet, already serialized.
+    unacked: VecDeque<(u64, Vec<u8>)>,
+    /// Sequence number of the last command received.
+    received: u64,
+}
+
+impl Delivery {
+    /// Starts numbering for session `generation` with `peer`. A restarted
+    /// node doesn't know about anything sent to its previous run, and
+    /// numbers its own commands from the start again.
+    fn start(&mut self, generation: u64, peer: Uuid) {
+        self.generation = generation;
+        if self.peer != Some(peer) {
+            self.peer = Some(peer);
+            self.unacked.clear();
+            self.received = 0;
+        }
+    }
+
+    fn acked(&mut self, ack: u64) {
+        while self.unacked.front().is_some_and(|(seq, _)| *seq <= ack) {
+            self.unacked.pop_front();
+        }
+    }
 }
 
 impl<C: DFutTrait> Connection<C> {
-    pub fn new(id: NodeId, resources: ResourceConfig) -> Self {
+    pub fn new(id: NodeId, addr: SocketAddr, resources: ResourceConfig) -> Self {
         Self {
             id,
+            addr,
             resources,
             session: Mutex::default(),
+            load: Mutex::new(Load {
+                in_use: ResourceConfig::new(),
+                running: HashMap::new(),
+                queued: VecDeque::new(),
+            }),
+            link: Mutex::new(Link {
+                generation: 0,
+                incarnation: None,
+                greeted: false,
+                compressed: false,
+                parked: Vec::new(),
+                requests: HashMap::new(),
+                partial: HashMap::new(),
+                locates: HashMap::new(),
+            }),
+            delivery: Arc::default(),
+            backlog: Arc::default(),
+            // Suspect until the first session, so requests made before the
+            // node connects wait for it.
+            state: Arc::new(Mutex::new(NodeState::Suspect)),
+            changed: Notify::new(),
         }
     }
 
-    pub fn start_local(&self, node: &'static Node<C>) {
-        let old = std::mem::replace(
-            &mut *self.session.lock().unwrap(),
-            Some(Session::new_local(node, self.id)),
+    /// Stands in for a member that was lost and forgotten: like the
+    /// connection it had, it drops what is sent and fails what is asked.
+    pub fn gone(id: NodeId) -> Self {
+        let conn = Self::new(
+            id,
+            SocketAddr::from(([0, 0, 0, 0], 0)),
+            ResourceConfig::new(),
         );
+        conn.lost();
+        conn
+    }
+
+    pub fn state(&self) -> NodeState {
+        *self.state.lock().unwrap()
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn member(&self) -> Member {
+        Member {
+            id: self.id,
+            addr: self.addr,
+            resources: self.resources.clone(),
+        }
+    }
+
+    pub fn start_local(&self, node: &'static Node<C>) {
+        *self.state.lock().unwrap() = NodeState::Alive;
+        let mut link = self.link.lock().unwrap();
+        link.incarnation = Some(node.incarnation());
+        link.greeted = true;
+        drop(link);
+        let old = self
+            .session
+            .lock()
+            .unwrap()
+            .replace(Session::new_local(node, self.id));
         assert!(old.is_none());
     }
 
-    pub fn start_remote(&self, node: &'static Node<C>, stream: TcpStream) {
-        std::mem::replace(
-            &mut *self.session.lock().unwrap(),
-            Some(Session::new_remote(node, self.id, stream)),
-        )
-        .map(Session::abort);
+    /// Starts a session over `stream`, which has completed the handshake
+    /// with `incarnation`, closing any previous one. Requests the previous
+    /// session had not sent yet carry over to the new one once it is greeted,
+    /// and the ones it sent are sent again unless the node got them.
+    /// `compressed` is whether both ends agreed to compress values.
+    pub fn start_remote(
+        &self,
+        node: &'static Node<C>,
+        stream: Stream,
+        incarnation: Uuid,
+        compressed: bool,
+        frame_len: usize,
+    ) {
+        let mut session = self.session.lock().unwrap();
+        let mut link = self.link.lock().unwrap();
+        link.generation += 1;
+        link.greeted = false;
+        link.compressed = compressed;
+        self.delivery
+            .lock()
+            .unwrap()
+            .start(link.generation, incarnation);
+        *session = Some(Session::new_remote(
+            node,
+            self,
+            link.generation,
+            stream,
+            frame_len,
+        ));
+    }
+
+    pub fn incarnation(&self) -> Option<Uuid> {
+        self.link.lock().unwrap().incarnation
+    }
+
+    /// Whether the connected node takes compressed values.
+    pub fn compressed(&self) -> bool {
+        self.link.lock().unwrap().compressed
+    }
+
+    fn is_open(&self) -> bool {
+        self.session
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(Session::is_open)
+    }
+
+    /// Sends everything queued on the session and closes it.
+    pub async fn close(&self) {
+        let session = self.session.lock().unwrap().take();
+        if let Some(task) = session.and_then(Session::close) {
+            let _ = task.await;
+        }
+    }
+
+    /// Waits until there is no open session to the connected node.
+    pub async fn closed(&self) {
+        loop {
+            let mut changed = pin!(self.changed.notified());
+            changed.as_mut().enable();
+            if !self.is_open() {
+                return;
+            }
+            changed.await;
+        }
+    }
+
+    /// Whether `incarnation` is a different process from the one last
+    /// greeted, the node having restarted in between.
+    pub fn restarted(&self, incarnation: Uuid) -> bool {
+        self.link
+            .lock()
+            .unwrap()
+            .incarnation
+            .is_some_and(|previous| previous != incarnation)
+    }
+
+    /// Records the incarnation the current session's peer handshook with,
+    /// then replays the commands parked while the node was unreachable.
+    pub fn greeted(&self, incarnation: Uuid) {
+        let session = self.session.lock().unwrap();
+        let mut link = self.link.lock().unwrap();
+        link.incarnation = Some(incarnation);
+        link.greeted = true;
+        *self.state.lock().unwrap() = NodeState::Alive;
+        for cmd in mem::take(&mut link.parked) {
+            self.forward(session.as_ref(), &mut link, cmd);
+        }
+    }
+
+    /// Called when session `generation` has closed, with the commands it
+    /// never got to send. Returns whether it was the current session, in
+    /// which case the node is suspect until it reconnects.
+    pub fn session_closed(&self, generation: u64, unsent: Vec<Command<C>>) -> bool {
+        let session = self.session.lock().unwrap();
+        let mut link = self.link.lock().unwrap();
+        let current = link.generation == generation;
+        if current {
+            link.greeted = false;
+            let mut state = self.state.lock().unwrap();
+            if *state != NodeState::Dead {
+                *state = NodeState::Suspect;
+            }
+        }
+        for cmd in unsent {
+            self.forward(session.as_ref(), &mut link, cmd);
+        }
+        if current {
+            self.changed.notify_waiters();
+        }
+        current
+    }
+
+    /// Declares the node lost if no session has replaced `generation` by
+    /// now. Returns whether it did.
+    pub fn expire(&self, generation: u64) -> bool {
+        if self.link.lock().unwrap().generation != generation {
+            return false;
+        }
+        self.lost();
+        true
+    }
+
+    /// Marks the node dead and fails every request still waiting on it.
+    pub fn lost(&self) {
+        let mut link = self.link.lock().unwrap();
+        *self.state.lock().unwrap() = NodeState::Dead;
+        // The node that spawned them places the calls again.
+        for cmd in link.parked.iter() {
+            self.backlog.sent(cmd);
+        }
+        link.incarnation = None;
+        link.greeted = false;
+        link.clear();
+        self.delivery.lock().unwrap().unacked.clear();
     }
 
     pub fn can_execute(&self, call: &impl DFutCall<C>) -> bool {
-        self.session.lock().unwrap().is_some()
+        self.state() != NodeState::Dead
+            && self.is_open()
+            && self.link.lock().unwrap().greeted
             && C::Resources::can_execute(call.get_resource_deps(), &self.resources)
     }
 
-    pub fn spawn<T: DFutValue, A: DFutCall<C, Output = T>>(
-        &self,
-        call: A,
-    ) -> Result<DFut<C, T>, A> {
-        if let Some(sess) = &*self.session.lock().unwrap() {
-            sess.spawn(call)
+    /// Whether `call` could be dispatched right now without waiting for
+    /// other tasks to release their resources.
+    pub fn has_capacity(&self, call: &impl DFutCall<C>) -> bool {
+        let load = self.load.lock().unwrap();
+        load.queued.is_empty()
+            && C::Resources::can_execute(call.get_resource_deps(), &self.available(&load))
+    }
+
+    fn available(&self, load: &Load<C>) -> ResourceConfig {
+        self.resources
+            .iter()
+            .map(|(res, &cap)| {
+                let used = load.in_use.get(res).copied().unwrap_or(0);
+                (res.clone(), cap.saturating_sub(used))
+            })
+            .collect()
+    }
+
+    fn fits(&self, load: &Load<C>, reqs: &[(String, usize)]) -> bool {
+        C::Resources::can_execute(
+            reqs.iter().map(|(res, amt)| (res.as_str(), *amt)),
+            &self.available(load),
+        )
+    }
+
+    pub fn info(&self) -> NodeInfo<'_> {
+        let load = self.load.lock().unwrap();
+        NodeInfo {
+            id: self.id,
+            resources: &self.resources,
+            available: self.available(&load),
+            state: self.state(),
+            running: load.running.len(),
+            queued: load.queued.len(),
+        }
+    }
+
+    /// Dispatches `call` if the connected node has the resources for it,
+    /// otherwise queues it until enough running tasks finish. Hands the call
+    /// back if the session is closed.
+    pub fn submit(&self, id: DFutId, call: C) -> Result<(), C> {
+        let session = self.session.lock().unwrap();
+        let sess = match &*session {
+            Some(sess) if sess.is_open() => sess,
+            _ => return Err(call),
+        };
+        let reqs: Vec<_> = call
+            .get_resource_deps()
+            .map(|(res, amt)| (res.to_owned(), amt))
+            .collect();
+        let mut load = self.load.lock().unwrap();
+        self.backlog.add();
+        if load.queued.is_empty() && self.fits(&load, &reqs) {
+            load.reserve(id, reqs);
+            self.dispatch(sess, id, call);
+        } else {
+            load.queued.push_back(QueuedCall { id, call, reqs });
+        }
+        Ok(())
+    }
+
+    /// Forgets all reservations on the connected node after it was lost and
+    /// returns the calls that were still queued for it.
+    pub fn reset(&self) -> Vec<(DFutId, C)> {
+        let mut load = self.load.lock().unwrap();
+        load.in_use.clear();
+        load.running.clear();
+        self.backlog.remove(load.queued.len());
+        load.queued
+            .drain(..)
+            .map(|QueuedCall { id, call, .. }| (id, call))
+            .collect()
+    }
+
+    /// Called when a task dispatched through this connection has finished.
+    /// Frees its resources and dispatches any queued calls that now fit.
+    pub fn release(&self, id: DFutId) {
+        let session = self.session.lock().unwrap();
+        let mut load = self.load.lock().unwrap();
+        load.release(id);
+        let Some(sess) = &*session else {
+            return;
+        };
+        while let Some(next) = load.queued.front() {
+            if !self.fits(&load, &next.reqs) {
+                break;
+            }
+            let QueuedCall { id, call, reqs } = load.queued.pop_front().unwrap();
+            load.reserve(id, reqs);
+            self.dispatch(sess, id, call);
+        }
+    }
+
+    fn dispatch(&self, sess: &Session<C>, id: DFutId, call: C) {
+        match sess.dispatch(id, call) {
+            Ok(()) if sess.is_local() => self.backlog.remove(1),
+            Ok(()) => {}
+            Err(cmd) => self.park(cmd),
+        }
+    }
+
+    pub fn queued_calls(&self) -> usize {
+        self.backlog.len()
+    }
+
+    /// Waits until fewer than `limit` calls are queued for the connected
+    /// node.
+    pub async fn room(&self, limit: usize) {
+        self.backlog.wait_for(|backlog| backlog.len() < limit).await
+    }
+
+    /// Waits until fewer than `limit` commands of any kind are queued on the
+    /// current session.
+    pub async fn command_room(&self, limit: usize) {
+        self.backlog
+            .wait_for(|backlog| backlog.commands() < limit)
+            .await
+    }
+
+    pub fn stats(&self) -> SessionStats {
+        let parked = self.link.lock().unwrap().parked.len();
+        SessionStats {
+            node: self.id,
+            queued_calls: self.queued_calls(),
+            queued_commands: parked + self.backlog.commands(),
+            unacked: self.delivery.lock().unwrap().unacked.len(),
+        }
+    }
+
+    /// Sends `cmd` to the connected node, holding on to it while the node
+    /// is reconnecting. Dropped if the node is dead.
+    pub fn send(&self, cmd: Command<C>) {
+        let session = self.session.lock().unwrap();
+        let mut link = self.link.lock().unwrap();
+        self.forward(session.as_ref(), &mut link, cmd);
+    }
+
+    fn forward(&self, sess: Option<&Session<C>>, link: &mut Link<C>, cmd: Command<C>) {
+        let cmd = match sess {
+            Some(sess) if link.greeted => match sess.send(cmd) {
+                Ok(()) => return,
+                Err(cmd) => cmd,
+            },
+            _ => cmd,
+        };
+        if self.state() != NodeState::Dead {
+            link.parked.push(cmd);
+        } else {
+            self.backlog.sent(&cmd);
+        }
+    }
+
+    fn park(&self, cmd: Command<C>) {
+        let mut link = self.link.lock().unwrap();
+        if self.state() != NodeState::Dead {
+            link.parked.push(cmd);
         } else {
-            Err(call)
+            self.backlog.sent(&cmd);
         }
     }
 
-    pub fn retrieve<T: Clone + DeserializeOwned + 'static>(
+    pub fn locate(&self, id: DFutId, lost: NodeId) -> impl Future<Output = Option<NodeId>> {
+        let session = self.session.lock().unwrap();
+        let mut link = self.link.lock().unwrap();
+        let (tx, rx) = oneshot::channel();
+        if self.state() != NodeState::Dead {
+            link.locates.entry(id).or_default().push(tx);
+            self.forward(session.as_ref(), &mut link, Command::Locate { id, lost });
+        }
+        async { rx.await.ok().flatten() }
+    }
+
+    pub fn located(&self, id: DFutId, node: Option<NodeId>) {
+        let mut link = self.link.lock().unwrap();
+        for channel in link.locates.remove(&id).into_iter().flatten() {
+            let _ = channel.send(node);
+        }
+    }
+
+    pub fn retrieve(
         &self,
         data: DFutData,
-    ) -> impl Future<Output = T> + Send {
-        if let Some(sess) = &*self.session.lock().unwrap() {
-            sess.retrieve(data)
-        } else {
-            panic!("No session");
+    ) -> Pin<Box<dyn Future<Output = Result<Object, DFutError>> + Send>> {
+        let session = self.session.lock().unwrap();
+        if let Some(sess) = session.as_ref().filter(|sess| sess.is_local()) {
+            return sess.retrieve_local(data);
+        }
+        let id = self.id;
+        let mut link = self.link.lock().unwrap();
+        if self.state() == NodeState::Dead {
+            return Box::pin(async move { Err(DFutError::NodeLost(id)) });
+        }
+        let (tx, rx) = oneshot::channel();
+        link.requests.insert(data.instance_id, tx);
+        self.forward(session.as_ref(), &mut link, Command::Retrieve { data });
+        Box::pin(async move {
+            // The request is dropped if the node is lost before answering.
+            let payload = rx.await.unwrap_or(Err(DFutError::NodeLost(id)))?;
+            Ok(Object::Bytes(payload))
+        })
+    }
+
+    /// Adds to a value being received in chunks, failing the request for
+    /// it once the value is over `limit`.
+    pub fn chunk(&self, id: InstanceId, data: Vec<u8>, limit: usize) {
+        let mut link = self.link.lock().unwrap();
+        if !link.requests.contains_key(&id) {
+            return;
+        }
+        let value = link.partial.entry(id).or_default();
+        if value.len() + data.len() <= limit {
+            value.extend(data);
+            return;
+        }
+        // The rest of the value is ignored as it arrives.
+        link.partial.remove(&id);
+        let channel = link.requests.remove(&id).unwrap();
+        let _ = channel.send(Err(DFutError::TooLarge(limit)));
+    }
+
+    /// Answers a retrieve with the value `payload` finishes, failing it
+    /// instead if the whole value is over `limit`.
+    pub fn completed(&self, id: InstanceId, payload: Payload, limit: usize) {
+        let mut link = self.link.lock().unwrap();
+        let payload = Self::assemble(&mut link, id, payload, limit);
+        if let Some(channel) = link.requests.remove(&id) {
+            let _ = channel.send(payload);
+        }
+    }
+
+    /// Like `completed`, for a value that was compressed with `compression`.
+    /// It is decompressed on the blocking pool, so neither the session nor
+    /// the runtime's workers are held up.
+    pub fn completed_compressed(
+        self: Arc<Self>,
+        node: &'static Node<C>,
+        id: InstanceId,
+        payload: Payload,
+        compression: Compression,
+    ) {
+        let limit = node.limits().value_len;
+        let payload = {
+            let mut link = self.link.lock().unwrap();
+            let payload = Self::assemble(&mut link, id, payload, limit);
+            if !link.requests.contains_key(&id) {
+                return;
+            }
+            payload
+        };
+        tokio::task::spawn_blocking(move || {
+            let payload =
+                payload.and_then(|bytes| node.compressor().decompress(compression, &bytes, limit));
+            self.completed(id, payload, limit);
+        });
+    }
+
+    /// Puts the value `payload` finishes back together with the chunks
+    /// received before it, failing if the whole value is over `limit`.
+    fn assemble(link: &mut Link<C>, id: InstanceId, payload: Payload, limit: usize) -> Payload {
+        let partial = link.partial.remove(&id);
+        let rest = payload?;
+        if partial.as_ref().map_or(0, Vec::len) + rest.len() > limit {
+            return Err(DFutError::TooLarge(limit));
+        }
+        match partial {
+            Some(mut value) => {
+                value.extend_from_slice(&rest);
+                Ok(value.into_boxed_slice())
+            }
+            None => Ok(rest),
         }
     }
 }
 
+/// Frames read off a stream ahead of the session handling them.
+const READ_AHEAD: usize = 16;
+
+/// Values larger than this are sent in chunks of this size, between other
+/// commands, so they don't hold up the session or need one huge frame.
+const CHUNK_LEN: usize = 256 * 1024;
+
+/// Smallest frame limit allowed, leaving room for a chunk and the command
+/// carrying it.
+pub const MIN_FRAME_LEN: usize = 2 * CHUNK_LEN;
+
 enum SessionType<C> {
     Local,
     Remote {
-        tasks: JoinSet<()>,
         call_channel: Sender<Command<C>>,
+        // Sending on this flushes the session's queue before closing it.
+        // Dropping it closes the session and hands the queue back to the
+        // connection, for the session replacing it.
+        close: oneshot::Sender<()>,
+        task: JoinHandle<()>,
+        backlog: Arc<Backlog>,
     },
 }
 
@@ -99,142 +773,567 @@ impl<C: DFutTrait> Session<C> {
         }
     }
 
-    fn new_remote(node: &'static Node<C>, connected_id: NodeId, stream: TcpStream) -> Self {
+    fn new_remote(
+        node: &'static Node<C>,
+        conn: &Connection<C>,
+        generation: u64,
+        stream: Stream,
+        frame_len: usize,
+    ) -> Self {
+        let connected_id = conn.id;
+        let node_state = conn.state.clone();
+        let delivery = conn.delivery.clone();
+        let backlog = conn.backlog.clone();
+        // Unbounded, as commands are sent from code that can't wait, like
+        // drops. Only submitted calls and values wait for room before they
+        // get here, so the queue limit is a soft one.
         let (sender, receiver) = mpsc::unbounded_channel();
+        let (close_tx, close_rx) = oneshot::channel();
         let sender_clone = sender.clone();
-        let mut tasks = JoinSet::new();
-        tasks.spawn(async move {
-            Self::task(SessionState {
+        let backlog_clone = backlog.clone();
+        let Stream { mut reader, writer } = stream;
+        // Frames are read on their own task, since a read can't be
+        // abandoned halfway through a frame when there is something to send.
+        let (frame_tx, frames) = mpsc::channel(READ_AHEAD);
+        // Frames are only refused past this node's own limit.
+        let max_len = node.limits().frame_len;
+        let reader = tokio::spawn(async move {
+            loop {
+                let res = reader.recv(max_len).await;
+                let done = !matches!(res, Ok(Some(_)));
+                if frame_tx.send(res).await.is_err() || done {
+                    break;
+                }
+            }
+        });
+        let task = tokio::spawn(async move {
+            let mut state = SessionState {
                 node,
-                stream,
+                connected_id,
+                writer,
+                frame_len,
+                frames,
                 sender,
                 receiver,
-                outstanding_requests: HashMap::new(),
-            })
-            .await
-            .unwrap()
+                close: close_rx,
+                last_seen: Instant::now(),
+                node_state,
+                generation,
+                delivery,
+                backlog,
+                resumed: false,
+                replaced: None,
+                transfers: VecDeque::new(),
+                batch: Batch::default(),
+                flush_at: None,
+            };
+            let res = Self::task(&mut state).await;
+            reader.abort();
+            if let Err(e) = &res {
+                log::info!("Session with node {connected_id} closed: {e}");
+            }
+            // Stop accepting commands and hand back the ones not sent yet,
+            // so they go out on the next session instead.
+            state.receiver.close();
+            let mut unsent: Vec<_> = state.replaced.take().into_iter().collect();
+            while let Ok(cmd) = state.receiver.try_recv() {
+                state.backlog.dequeued();
+                if !matches!(cmd, Command::Ping | Command::Pong) {
+                    unsent.push(cmd);
+                }
+            }
+            // The chunks already sent go out again first, so the rest of
+            // each value follows on from them.
+            for transfer in state.transfers.drain(..) {
+                unsent.push(transfer.into_rest());
+            }
+            let timed_out = res.is_err_and(|e| e.kind() == ErrorKind::TimedOut);
+            node.session_closed(connected_id, generation, unsent, timed_out);
         });
         Self {
             node,
             connected_id,
             session_type: SessionType::Remote {
                 call_channel: sender_clone,
-                tasks,
+                close: close_tx,
+                task,
+                backlog: backlog_clone,
             },
         }
     }
 
-    fn spawn<T, A: DFutCall<C, Output = T>>(&self, call: A) -> Result<DFut<C, T>, A> {
-        let id = DFutId::new_v4();
+    /// Flushes and closes a remote session, returning its task.
+    fn close(self) -> Option<JoinHandle<()>> {
+        match self.session_type {
+            SessionType::Local => None,
+            SessionType::Remote { close, task, .. } => {
+                let _ = close.send(());
+                Some(task)
+            }
+        }
+    }
+
+    fn is_local(&self) -> bool {
+        matches!(self.session_type, SessionType::Local)
+    }
+
+    fn is_open(&self) -> bool {
+        match &self.session_type {
+            SessionType::Local => true,
+            SessionType::Remote { call_channel, .. } => !call_channel.is_closed(),
+        }
+    }
+
+    /// Queues `cmd` to be written to the connected node, handing it back if
+    /// the session has closed.
+    fn send(&self, cmd: Command<C>) -> Result<(), Command<C>> {
         match &self.session_type {
-            SessionType::Local => self.node.run_task(id, call.to_call_type()),
-            SessionType::Remote { call_channel, .. } => {
-                if call_channel.is_closed() {
-                    return Err(call);
-                } else {
-                    call_channel
-                        .send(Command::Call {
-                            id,
-                            call: call.to_call_type(),
-                        })
-                        .unwrap()
-                }
+            SessionType::Local => panic!("Attempting to send to local session"),
+            SessionType::Remote {
+                call_channel,
+                backlog,
+                ..
+            } => {
+                backlog.queued();
+                call_channel.send(cmd).map_err(|e| {
+                    backlog.dequeued();
+                    e.0
+                })
             }
-        };
-        Ok(DFut::new(self.node, self.connected_id, id))
+        }
     }
 
-    pub fn retrieve<T: Clone + DeserializeOwned + 'static>(
-        &self,
-        data: DFutData,
-    ) -> impl Future<Output = T> + Send {
+    fn dispatch(&self, id: DFutId, call: C) -> Result<(), Command<C>> {
         match &self.session_type {
             SessionType::Local => {
-                let pending = self.node.get_from_store(data);
-                Box::pin(async { Arc::unwrap_or_clone(cast(pending.resolve().await).unwrap()) })
-                    as Pin<Box<dyn Future<Output = T> + Send>>
-            }
-            SessionType::Remote { call_channel, .. } => {
-                let (tx, rx) = oneshot::channel();
-                call_channel
-                    .send(Command::Retrieve {
-                        data,
-                        channel: Some(tx),
-                    })
-                    .unwrap();
-                Box::pin(async { serde_cbor::from_slice(&rx.await.unwrap()).unwrap() })
+                let node = self.node;
+                let connected_id = self.connected_id;
+                node.run_task(id, call, move || node.release(connected_id, id));
+                Ok(())
             }
+            SessionType::Remote { .. } => self.send(Command::Call { id, call }),
         }
     }
 
-    fn abort(self) {
-        match self.session_type {
-            SessionType::Local { .. } => panic!("Attempting to abort local session"),
-            SessionType::Remote { mut tasks, .. } => tasks.abort_all(),
-        }
+    fn retrieve_local(
+        &self,
+        data: DFutData,
+    ) -> Pin<Box<dyn Future<Output = Result<Object, DFutError>> + Send>> {
+        let pending = self.node.get_from_store(data, self.node.id());
+        Box::pin(pending.resolve())
     }
 
-    async fn task(mut state: SessionState<C>) -> io::Result<()> {
+    async fn task(state: &mut SessionState<C>) -> io::Result<()> {
+        let heartbeat = state.node.heartbeat();
+        let batching = state.node.batching();
+        let mut ticks = time::interval(heartbeat.interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
         loop {
             tokio::select! {
-                cmd = state.receiver.recv() => match cmd {
-                    Some(cmd) => Self::send_cmd(&mut state, cmd).await?,
+                // Commands wait until the node has said what it got from the
+                // previous session, so the ones it missed go first. Pings are
+                // sent right away, as the first tick completes immediately.
+                cmd = state.receiver.recv(), if state.resumed => match cmd {
+                    Some(cmd) => {
+                        state.backlog.dequeued();
+                        Self::send_cmd(state, cmd).await?;
+                        // Take whatever else is queued along with it.
+                        while state.batch.len() < batching.max_commands {
+                            let Ok(cmd) = state.receiver.try_recv() else {
+                                break;
+                            };
+                            state.backlog.dequeued();
+                            Self::send_cmd(state, cmd).await?;
+                        }
+                        if batching.flush_interval.is_zero() {
+                            Self::flush(state).await?;
+                        } else if !state.batch.is_empty() && state.flush_at.is_none() {
+                            state.flush_at = Some(Instant::now() + batching.flush_interval);
+                        }
+                    }
                     None => break,
                 },
 
-                res = state.stream.readable() => res.map(|_| Self::recv_cmd(&mut state))?.await?,
+                _ = time::sleep_until(state.flush_at.unwrap_or_else(Instant::now)),
+                    if state.flush_at.is_some() => Self::flush(state).await?,
+
+                frame = state.frames.recv() => Self::recv_cmd(state, frame).await?,
+
+                _ = future::ready(()), if state.resumed && !state.transfers.is_empty() => {
+                    Self::send_chunk(state).await?
+                }
+
+                _ = ticks.tick() => {
+                    let silent = state.last_seen.elapsed();
+                    if silent > heartbeat.timeout {
+                        return Err(io::Error::new(ErrorKind::TimedOut, "heartbeat timed out"));
+                    }
+                    if silent > 2 * heartbeat.interval {
+                        state.set_node_state(NodeState::Suspect);
+                    }
+                    Self::send_cmd(state, Command::Ping).await?;
+                    Self::flush(state).await?;
+                }
+
+                res = &mut state.close => {
+                    if res.is_ok() {
+                        while let Ok(cmd) = state.receiver.try_recv() {
+                            state.backlog.dequeued();
+                            Self::send_cmd(state, cmd).await?;
+                        }
+                        while !state.transfers.is_empty() {
+                            Self::send_chunk(state).await?;
+                        }
+                        Self::flush(state).await?;
+                        Self::linger(state, heartbeat.timeout).await?;
+                    }
+                    break;
+                }
             };
         }
         Ok(())
     }
 
+    /// Half-closes the stream and waits for the peer to close its end.
+    /// Dropping a stream with unread data resets it, which can discard what
+    /// was just sent before the peer reads it.
+    async fn linger(state: &mut SessionState<C>, timeout: Duration) -> io::Result<()> {
+        state.writer.close().await?;
+        let drain = async { while let Some(Ok(Some(_))) = state.frames.recv().await {} };
+        let _ = time::timeout(timeout, drain).await;
+        Ok(())
+    }
+
+    /// Adds `cmd` to the batch being gathered, writing the batch out if that
+    /// fills it. Commands too large for a frame of their own aren't sent.
     async fn send_cmd(state: &mut SessionState<C>, mut cmd: Command<C>) -> io::Result<()> {
-        if let Command::Retrieve { data, channel } = &mut cmd {
-            state
-                .outstanding_requests
-                .insert(data.instance_id, channel.take().unwrap());
+        let max_len = state.frame_len;
+        loop {
+            cmd = match cmd {
+                Command::Completed {
+                    id,
+                    payload: Ok(value),
+                    compression,
+                } if value.len() > CHUNK_LEN => {
+                    state.transfers.push_back(Transfer {
+                        id,
+                        value,
+                        compression,
+                        sent: 0,
+                    });
+                    return Ok(());
+                }
+                cmd => cmd,
+            };
+            let encoded = {
+                let mut delivery = state.delivery.lock().unwrap();
+                let heartbeat = matches!(cmd, Command::Ping | Command::Pong);
+                if !heartbeat && delivery.generation != state.generation {
+                    // Numbering has moved on to a newer session, which sends
+                    // this instead.
+                    state.replaced = Some(cmd);
+                    return Err(io::Error::other("session replaced"));
+                }
+                let seq = if heartbeat { 0 } else { delivery.sent + 1 };
+                state.backlog.sent(&cmd);
+                let envelope = Envelope {
+                    seq,
+                    ack: delivery.received,
+                    cmd,
+                };
+                match state.node.codec().encode(&envelope) {
+                    // Too large even for a frame of its own, or for its
+                    // length to be written. Turned away before it is
+                    // numbered, so it isn't kept to be sent again either.
+                    Ok(payload) if !Batch::default().fits(&payload, max_len) => {
+                        Err((envelope.cmd, DFutError::TooLarge(max_len)))
+                    }
+                    Ok(payload) => {
+                        // The batch so far goes out first, in a frame of its
+                        // own.
+                        let full =
+                            (!state.batch.fits(&payload, max_len)).then(|| state.batch.take());
+                        state.batch.push(&payload)?;
+                        if !heartbeat {
+                            delivery.sent = seq;
+                            delivery.unacked.push_back((seq, payload));
+                        }
+                        Ok(full)
+                    }
+                    Err(e) => Err((envelope.cmd, DFutError::Serialize(e))),
+                }
+            };
+            match encoded {
+                Ok(full) => {
+                    if let Some(frame) = full {
+                        state.writer.send(frame).await?;
+                    }
+                    return Self::flush_if_full(state).await;
+                }
+                Err((unsent, error)) => match Self::unsendable(state, unsent, error) {
+                    Some(instead) => cmd = instead,
+                    None => return Ok(()),
+                },
+            }
         }
-        let payload = serde_cbor::to_vec(&cmd).unwrap();
-        state
-            .stream
-            .write_u32(payload.len().try_into().unwrap())
-            .await?;
-        state.stream.write_all(&payload).await
     }
 
-    async fn recv_cmd(state: &mut SessionState<C>) -> io::Result<()> {
-        let mut len_buf = [0; 4];
-        match state.stream.try_read(&mut len_buf) {
-            Ok(n) => {
-                state.stream.read_exact(&mut len_buf[n..]).await?;
+    /// Answers whatever waits on a command that couldn't be encoded with
+    /// `error`, returning what to send the node in its place, if anything.
+    fn unsendable(
+        state: &SessionState<C>,
+        cmd: Command<C>,
+        error: DFutError,
+    ) -> Option<Command<C>> {
+        let conn = state.node.connection(state.connected_id);
+        match cmd {
+            Command::Call { id, .. } => Some(Command::Failed { id, error }),
+            Command::Completed {
+                id, payload: Ok(_), ..
+            } => Some(Command::Completed {
+                id,
+                payload: Err(error),
+                compression: None,
+            }),
+            Command::Retrieve { data } => {
+                let limit = state.node.limits().value_len;
+                conn.completed(data.instance_id, Err(error), limit);
+                None
+            }
+            Command::Locate { id, .. } => {
+                conn.located(id, None);
+                None
             }
-            Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
-            Err(e) => return Err(e),
+            // Nothing waits on the rest.
+            _ => None,
         }
+    }
+
+    async fn flush_if_full(state: &mut SessionState<C>) -> io::Result<()> {
+        let max_commands = state.node.batching().max_commands;
+        if state.batch.len() >= max_commands || state.batch.bytes() >= CHUNK_LEN {
+            Self::flush(state).await?;
+        }
+        Ok(())
+    }
+
+    /// Writes out the commands gathered so far as one frame.
+    async fn flush(state: &mut SessionState<C>) -> io::Result<()> {
+        state.flush_at = None;
+        if state.batch.is_empty() {
+            return Ok(());
+        }
+        let frame = state.batch.take();
+        state.writer.send(frame).await
+    }
+
+    /// Sends the next chunk of the value at the front of the queue, and
+    /// moves it to the back, so values being sent take turns.
+    async fn send_chunk(state: &mut SessionState<C>) -> io::Result<()> {
+        let mut transfer = state.transfers.pop_front().unwrap();
+        let end = (transfer.sent + CHUNK_LEN).min(transfer.value.len());
+        let data = transfer.value[transfer.sent..end].to_vec();
+        transfer.sent = end;
+        let id = transfer.id;
+        let compression = transfer.compression;
+        let done = end == transfer.value.len();
+        if !done {
+            state.transfers.push_back(transfer);
+        }
+        Self::send_cmd(state, Command::Chunk { id, data }).await?;
+        if done {
+            let payload = Ok(Box::default());
+            let cmd = Command::Completed {
+                id,
+                payload,
+                compression,
+            };
+            Self::send_cmd(state, cmd).await?;
+        }
+        Self::flush(state).await
+    }
 
-        let len = u32::from_be_bytes(len_buf);
-        let mut buf = vec![0; len as usize];
+    /// Sends again whatever the node did not get from previous sessions,
+    /// given the last command it got was `ack`.
+    async fn resume(state: &mut SessionState<C>, ack: u64) -> io::Result<()> {
+        let missed: Vec<_> = {
+            let mut delivery = state.delivery.lock().unwrap();
+            delivery.acked(ack);
+            delivery
+                .unacked
+                .iter()
+                .map(|(_, payload)| payload.clone())
+                .collect()
+        };
+        let max_len = state.frame_len;
+        for payload in missed {
+            if !state.batch.fits(&payload, max_len) {
+                Self::flush(state).await?;
+            }
+            state.batch.push(&payload)?;
+            Self::flush_if_full(state).await?;
+        }
+        Self::flush(state).await?;
+        state.resumed = true;
+        Ok(())
+    }
 
-        state.stream.read_exact(&mut buf).await?;
-        let cmd: Command<C> = serde_cbor::from_slice(&buf).unwrap();
+    async fn recv_cmd(
+        state: &mut SessionState<C>,
+        frame: Option<io::Result<Option<Vec<u8>>>>,
+    ) -> io::Result<()> {
+        let buf = match frame {
+            Some(Ok(Some(buf))) => buf,
+            Some(Err(e)) => return Err(e),
+            Some(Ok(None)) | None => {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, "early eof"))
+            }
+        };
+        state.last_seen = Instant::now();
+        state.set_node_state(NodeState::Alive);
+        for envelope in Batch::split(&buf)? {
+            Self::recv_envelope(state, envelope).await?;
+        }
+        Ok(())
+    }
+
+    /// Makes do with a command that couldn't be decoded. A call whose
+    /// arguments can't be read fails as if its body had, so its futures
+    /// don't wait forever. Its id can only be read past the arguments with a
+    /// self-describing codec. Anything else closes the session.
+    fn undecodable(state: &SessionState<C>, buf: &[u8], e: String) -> io::Result<Envelope<C>> {
+        match state.node.codec().decode::<Envelope<IgnoredAny>>(buf) {
+            Ok(Envelope {
+                seq,
+                ack,
+                cmd: Command::Call { id, .. },
+            }) => Ok(Envelope {
+                seq,
+                ack,
+                cmd: Command::Failed {
+                    id,
+                    error: DFutError::Deserialize(e),
+                },
+            }),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("undecodable command from node {}: {e}", state.connected_id),
+            )),
+        }
+    }
+
+    async fn recv_envelope(state: &mut SessionState<C>, buf: &[u8]) -> io::Result<()> {
+        let Envelope { seq, ack, cmd } = match state.node.codec().decode(buf) {
+            Ok(envelope) => envelope,
+            Err(e) => Self::undecodable(state, buf, e)?,
+        };
+        if !state.resumed {
+            Self::resume(state, ack).await?;
+        }
+        {
+            let mut delivery = state.delivery.lock().unwrap();
+            delivery.acked(ack);
+            if seq != 0 {
+                if seq <= delivery.received {
+                    // Already got it before the previous session dropped.
+                    return Ok(());
+                }
+                delivery.received = seq;
+            }
+        }
+        let node = state.node;
+        let connected_id = state.connected_id;
         match cmd {
-            Command::Call { id, call } => state.node.run_task(id, call),
-            Command::Retrieve { data, .. } => {
-                let sender = state.sender.clone();
-                let node = state.node;
+            Command::Call { id, call } => node.run_task(id, call, move || {
+                node.connection(connected_id).send(Command::Finished { id });
+            }),
+            Command::Failed { id, error } => node.fail_task(id, error, move || {
+                node.connection(connected_id).send(Command::Finished { id });
+            }),
+            Command::Retrieve { data } => {
                 tokio::spawn(async move {
                     let id = data.instance_id;
-                    let val = node.get_from_store(data).resolve().await;
-                    let payload = serde_cbor::to_vec(&val).unwrap().into_boxed_slice();
-                    sender.send(Command::Completed { id, payload }).unwrap();
+                    // The task was placed on a previous run of this node and
+                    // will never show up here.
+                    if data
+                        .incarnation
+                        .is_some_and(|inc| inc != node.incarnation())
+                    {
+                        let payload = Err(DFutError::NodeLost(node.id()));
+                        return node.connection(connected_id).send(Command::Completed {
+                            id,
+                            payload,
+                            compression: None,
+                        });
+                    }
+                    let payload = node
+                        .get_from_store(data, connected_id)
+                        .resolve()
+                        .await
+                        .and_then(|obj| obj.into_bytes(node.codec()));
+                    let conn = node.connection(connected_id);
+                    // Values wait to be queued like calls do, rather than
+                    // piling up behind a slow link.
+                    conn.command_room(node.queue_limit()).await;
+                    let (payload, compression) = match payload {
+                        Ok(bytes) if conn.compressed() => {
+                            let compress = move || node.compressor().compress(bytes);
+                            match tokio::task::spawn_blocking(compress).await {
+                                Ok((bytes, compression)) => (Ok(bytes), compression),
+                                Err(e) => (Err(e.into()), None),
+                            }
+                        }
+                        payload => (payload, None),
+                    };
+                    conn.send(Command::Completed {
+                        id,
+                        payload,
+                        compression,
+                    });
+                });
+            }
+            Command::Chunk { id, data } => {
+                let limit = node.limits().value_len;
+                node.connection(connected_id).chunk(id, data, limit)
+            }
+            Command::Completed {
+                id,
+                payload,
+                compression,
+            } => {
+                let conn = node.connection(connected_id);
+                match compression {
+                    Some(compression) => conn.completed_compressed(node, id, payload, compression),
+                    None => conn.completed(id, payload, node.limits().value_len),
+                }
+            }
+            Command::Finished { id } => node.release(connected_id, id),
+            Command::Dropped { data } => {
+                // Like a retrieve, only counts against the current run.
+                if data.incarnation.is_none_or(|inc| inc == node.incarnation()) {
+                    node.forget(data);
+                }
+            }
+            Command::Freed { id } => node.freed(id),
+            Command::Locate { id, lost } => {
+                tokio::spawn(async move {
+                    let located = node.lineage().locate(id, lost).await;
+                    node.connection(connected_id)
+                        .send(Command::Located { id, node: located });
                 });
             }
-            Command::Completed { id, payload } => state
-                .outstanding_requests
-                .remove(&id)
-                .unwrap()
-                .send(payload)
-                .unwrap(),
+            Command::Located { id, node: located } => {
+                node.connection(connected_id).located(id, located)
+            }
+            Command::Members { members } => node.add_members(members),
+            Command::Shutdown { code } => node.shutdown_handle().shutdown(code),
+            Command::Ping => {
+                state.backlog.queued();
+                if state.sender.send(Command::Pong).is_err() {
+                    state.backlog.dequeued();
+                }
+            }
+            Command::Pong => {}
         };
         Ok(())
     }
@@ -242,13 +1341,60 @@ impl<C: DFutTrait> Session<C> {
 
 struct SessionState<C: DFutTrait> {
     node: &'static Node<C>,
-    stream: TcpStream,
+    connected_id: NodeId,
+    writer: Box<dyn FrameWriter>,
+    /// Largest frame written, the smaller of the two nodes' limits.
+    frame_len: usize,
+    frames: mpsc::Receiver<io::Result<Option<Vec<u8>>>>,
     sender: Sender<Command<C>>,
     receiver: Receiver<Command<C>>,
-    outstanding_requests: HashMap<InstanceId, oneshot::Sender<Box<[u8]>>>,
+    close: oneshot::Receiver<()>,
+    last_seen: Instant,
+    node_state: Arc<Mutex<NodeState>>,
+    generation: u64,
+    delivery: Arc<Mutex<Delivery>>,
+    backlog: Arc<Backlog>,
+    /// Whether commands missed by the node have been sent again.
+    resumed: bool,
+    /// A command this session was about to send when it was replaced.
+    replaced: Option<Command<C>>,
+    /// Values being sent in chunks.
+    transfers: VecDeque<Transfer>,
+    /// Commands gathered to be written as the next frame.
+    batch: Batch,
+    /// When the batch is written if it doesn't fill up first.
+    flush_at: Option<Instant>,
+}
+
+/// A value being sent to the node a chunk at a time, in answer to a
+/// retrieve.
+struct Transfer {
+    id: InstanceId,
+    value: Box<[u8]>,
+    compression: Option<Compression>,
+    /// Bytes of `value` sent so far.
+    sent: usize,
 }
 
-fn cast<T: 'static>(val: Value) -> Option<Arc<T>> {
-    (val.as_ref().type_id() == TypeId::of::<T>())
-        .then(|| unsafe { Arc::from_raw(Arc::into_raw(val).cast()) })
+impl Transfer {
+    /// What is left to send, as a command to send on the next session.
+    fn into_rest<C>(self) -> Command<C> {
+        Command::Completed {
+            id: self.id,
+            payload: Ok(self.value[self.sent..].into()),
+            compression: self.compression,
+        }
+    }
+}
+
+impl<C: DFutTrait> SessionState<C> {
+    /// Updates the node's liveness from its heartbeats. A dead node stays
+    /// dead until it is greeted on a new session, even if this one hears
+    /// from it on another thread in the meantime.
+    fn set_node_state(&self, new: NodeState) {
+        let mut node_state = self.node_state.lock().unwrap();
+        if *node_state != NodeState::Dead {
+            *node_state = new;
+        }
+    }
 }
@@ -1,3 +1,4 @@
+use crate::error::DFutError;
 use crate::resource::Resources;
 use crate::types::{DFutId, InstanceId, NodeId, Value};
 use crate::Node;
@@ -7,12 +8,23 @@ use std::any::Any;
 use std::cell::RefCell;
 use std::future::{Future, IntoFuture};
 use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
 use std::pin::Pin;
+use uuid::Uuid;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct DFutData {
     pub node: NodeId,
+    /// The node that spawned the task and can re-execute it.
+    pub origin: NodeId,
+    /// The process `node` was running when the task was placed there. A
+    /// node that has restarted since no longer has the task.
+    pub incarnation: Option<Uuid>,
     pub id: DFutId,
+    /// Identifies this copy of the future. Copies are counted without
+    /// messaging the node holding the result: each one reports the instance
+    /// it was cloned from and how many times it was cloned itself when it is
+    /// awaited or dropped, and the result is freed once every copy has.
     pub instance_id: InstanceId,
     pub parent: InstanceId,
     pub children: i32,
@@ -26,10 +38,12 @@ pub struct DFut<C: DFutTrait, T> {
 }
 
 impl<C: DFutTrait, T> DFut<C, T> {
-    pub fn new(node: &'static Node<C>, node_id: NodeId, id: DFutId) -> Self {
+    pub(crate) fn new(node: &'static Node<C>, node_id: NodeId, id: DFutId) -> Self {
         Self {
             data: RefCell::new(DFutData {
                 node: node_id,
+                origin: node.id(),
+                incarnation: node.connection(node_id).incarnation(),
                 id,
                 instance_id: InstanceId::new_v4(),
                 parent: InstanceId::nil(),
@@ -41,43 +55,63 @@ impl<C: DFutTrait, T> DFut<C, T> {
     }
 }
 
+impl DFutData {
+    /// Makes another copy of the future, counted like any clone of it.
+    pub fn share(&mut self) -> Self {
+        self.children += 1;
+        Self {
+            instance_id: InstanceId::new_v4(),
+            parent: self.instance_id,
+            children: 0,
+            ..self.clone()
+        }
+    }
+}
+
 impl<C: DFutTrait, T> Clone for DFut<C, T> {
     fn clone(&self) -> Self {
-        let mut data = self.data.borrow_mut();
-        data.children += 1;
-        let &DFutData {
-            node,
-            id,
-            instance_id: parent,
-            ..
-        } = &*data;
         Self {
-            data: RefCell::new(DFutData {
-                node,
-                id,
-                instance_id: InstanceId::new_v4(),
-                parent,
-                children: 0,
-            }),
+            data: RefCell::new(self.data.borrow_mut().share()),
             node: self.node,
             _marker: PhantomData,
         }
     }
 }
 
-impl<C: DFutTrait, T> Into<DFutData> for DFut<C, T> {
-    fn into(self) -> DFutData {
-        self.data.into_inner()
+/// Dropping a future without awaiting it tells the node holding the result
+/// that this copy no longer needs it.
+impl<C: DFutTrait, T> Drop for DFut<C, T> {
+    fn drop(&mut self) {
+        self.node.dropped(self.data.get_mut().clone());
+    }
+}
+
+/// Hands over this copy of the future, leaving whoever ends up with the data
+/// to await it.
+impl<C: DFutTrait, T> From<DFut<C, T>> for DFutData {
+    fn from(value: DFut<C, T>) -> Self {
+        ManuallyDrop::new(value).data.borrow().clone()
     }
 }
 
+impl<C: DFutTrait, T: Clone + DeserializeOwned + 'static> DFut<C, T> {
+    /// Waits for the value, returning an error instead of panicking if the
+    /// task failed or its result could not be retrieved.
+    pub fn try_await(self) -> impl Future<Output = Result<T, DFutError>> + Send + 'static {
+        self.node.retrieve(self.into())
+    }
+}
+
+/// Awaiting a `DFut` directly panics if the task failed. Use
+/// [`DFut::try_await`] to handle the error instead.
 impl<C: DFutTrait, T: Clone + DeserializeOwned + 'static> IntoFuture for DFut<C, T> {
     type Output = T;
 
     type IntoFuture = Pin<Box<dyn Future<Output = Self::Output> + Send>>;
 
     fn into_future(self) -> Self::IntoFuture {
-        Box::pin(self.node.retrieve(self.into()))
+        let fut = self.try_await();
+        Box::pin(async move { fut.await.unwrap_or_else(|e| panic!("{e}")) })
     }
 }
 
@@ -105,12 +139,42 @@ pub trait DFutTrait:
     DFutCall<Self, Output = Value> + Serialize + DeserializeOwned + Send + Sync + 'static
 {
     type Resources: Resources;
+
+    /// Identifies the calls, their argument types and result types, in
+    /// order. Nodes with a different fingerprint would read each other's
+    /// calls as the wrong ones, so sessions between them are refused.
+    ///
+    /// Only the text of the signatures is hashed, as written in
+    /// `dfut_procs!`. Changing how a type is defined, or naming it through a
+    /// different path, isn't caught, and neither is changing what a call's
+    /// body does.
+    const FINGERPRINT: u64;
+
+    /// The futures passed to the call as arguments, in order.
+    fn dfut_deps_mut(&mut self) -> Vec<&mut DFutData>;
+}
+
+/// Hashes the description of a `dfut_procs!` block into its fingerprint.
+/// FNV-1a, so the same calls give the same fingerprint on every build.
+pub const fn fingerprint(schema: &str) -> u64 {
+    let bytes = schema.as_bytes();
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+        i += 1;
+    }
+    hash
 }
 
 pub trait DFutCall<C: DFutTrait>: Into<C> + Sized {
     type Output: DFutValue;
 
-    fn run(self, node: &'static Node<C>) -> impl Future<Output = Self::Output> + Send + 'static;
+    fn run(
+        self,
+        node: &'static Node<C>,
+    ) -> impl Future<Output = Result<Self::Output, DFutError>> + Send + 'static;
 
     fn to_call_type(self) -> C {
         self.into()
@@ -131,7 +195,7 @@ pub trait MaybeFutTrait<T>: Into<MaybeFut<T>> + Send + 'static {
     fn retrieve<C: DFutTrait>(
         self,
         node: &'static Node<C>,
-    ) -> impl std::future::Future<Output = T> + Send + 'static;
+    ) -> impl std::future::Future<Output = Result<T, DFutError>> + Send + 'static;
 }
 
 impl<T: Clone + DeserializeOwned + Send + 'static> MaybeFutTrait<T> for MaybeFut<T> {
@@ -142,15 +206,10 @@ impl<T: Clone + DeserializeOwned + Send + 'static> MaybeFutTrait<T> for MaybeFut
         }
     }
 
-    fn retrieve<C: DFutTrait>(
-        self,
-        node: &'static Node<C>,
-    ) -> impl std::future::Future<Output = T> + Send + 'static {
-        async {
-            match self {
-                Self::Val(x) => x,
-                Self::Fut(data) => node.retrieve(data).await,
-            }
+    async fn retrieve<C: DFutTrait>(self, node: &'static Node<C>) -> Result<T, DFutError> {
+        match self {
+            Self::Val(x) => Ok(x),
+            Self::Fut(data) => node.retrieve(data).await,
         }
     }
 }
@@ -164,8 +223,8 @@ impl<T: Into<MaybeFut<T>> + Send + 'static> MaybeFutTrait<T> for T {
         None
     }
 
-    async fn retrieve<C: DFutTrait>(self, _node: &'static Node<C>) -> T {
-        self
+    async fn retrieve<C: DFutTrait>(self, _node: &'static Node<C>) -> Result<T, DFutError> {
+        Ok(self)
     }
 }
 
@@ -184,8 +243,8 @@ impl<C: DFutTrait, T: Clone + DeserializeOwned + Send + 'static> MaybeFutTrait<T
     fn retrieve<C2: DFutTrait>(
         self,
         _node: &'static Node<C2>,
-    ) -> impl std::future::Future<Output = T> + Send + 'static {
-        IntoFuture::into_future(self)
+    ) -> impl std::future::Future<Output = Result<T, DFutError>> + Send + 'static {
+        self.try_await()
     }
 }
 
@@ -0,0 +1,60 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinError;
+
+use crate::types::NodeId;
+
+/// Why a `DFut` could not produce its value.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum DFutError {
+    /// The task body panicked, with the panic message if there was one.
+    Panicked(String),
+    /// The node holding the task or its result went away.
+    NodeLost(NodeId),
+    /// The value could not be serialized by the node that holds it.
+    Serialize(String),
+    /// The value could not be deserialized into the expected type.
+    Deserialize(String),
+    /// The task was cancelled before it completed.
+    Cancelled,
+    /// The value was spilled to disk and could not be read back.
+    Spill(String),
+    /// The value, or the call that would produce it, was larger than the
+    /// limit in bytes set with `NodeBuilder::max_frame_len` or
+    /// `NodeBuilder::max_value_len`.
+    TooLarge(usize),
+}
+
+impl fmt::Display for DFutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Panicked(msg) => write!(f, "task panicked: {msg}"),
+            Self::NodeLost(node) => write!(f, "lost connection to node {node}"),
+            Self::Serialize(msg) => write!(f, "failed to serialize value: {msg}"),
+            Self::Deserialize(msg) => write!(f, "failed to deserialize value: {msg}"),
+            Self::Cancelled => write!(f, "task was cancelled"),
+            Self::Spill(msg) => write!(f, "failed to read spilled value: {msg}"),
+            Self::TooLarge(limit) => write!(f, "larger than the limit of {limit} bytes"),
+        }
+    }
+}
+
+impl std::error::Error for DFutError {}
+
+impl From<JoinError> for DFutError {
+    fn from(err: JoinError) -> Self {
+        if !err.is_panic() {
+            return Self::Cancelled;
+        }
+        let payload = err.into_panic();
+        let msg = if let Some(msg) = payload.downcast_ref::<&str>() {
+            msg.to_string()
+        } else if let Some(msg) = payload.downcast_ref::<String>() {
+            msg.clone()
+        } else {
+            String::new()
+        };
+        Self::Panicked(msg)
+    }
+}
@@ -1,11 +1,25 @@
+mod cache;
+mod codec;
+mod compression;
 mod connection;
 mod dfut;
+mod error;
+mod lineage;
 pub mod macros;
 mod node;
 mod protocol;
 pub mod resource;
+pub mod scheduler;
+mod stats;
 mod store;
+pub mod transport;
 mod types;
 
-pub use node::spawn;
-pub use node::Node;
+pub use codec::Codec;
+pub use compression::Compression;
+pub use connection::NodeState;
+pub use dfut::DFut;
+pub use error::DFutError;
+pub use node::{in_context, put, shutdown, spawn, spawn_with_retries, stats, submit};
+pub use node::{Node, NodeBuilder, ShutdownHandle};
+pub use stats::{CompressionStats, NodeStats, SessionStats, StoreStats};
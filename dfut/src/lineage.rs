@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::mem;
+use std::pin::pin;
+use std::sync::Mutex;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::sync::Notify;
+
+use crate::dfut::{DFutData, DFutTrait};
+use crate::types::{DFutId, NodeId};
+
+/// Calls this node dispatched to other nodes, kept so they can be re-executed
+/// elsewhere if the node running them is lost.
+///
+/// The futures a call was passed are consumed when it runs, so each entry
+/// holds its own copy of them to keep their results alive for a re-execution.
+/// Entries hand those copies back when they're removed, for the node to drop.
+pub struct Lineage {
+    entries: Mutex<HashMap<DFutId, LineageEntry>>,
+    changed: Notify,
+}
+
+struct LineageEntry {
+    node: NodeId,
+    call: Box<[u8]>,
+    deps: Vec<DFutData>,
+    retries: usize,
+}
+
+impl Lineage {
+    pub fn new() -> Self {
+        Self {
+            entries: Mutex::default(),
+            changed: Notify::new(),
+        }
+    }
+
+    pub fn record<C: DFutTrait + Serialize>(
+        &self,
+        id: DFutId,
+        node: NodeId,
+        call: &mut C,
+        retries: usize,
+    ) {
+        let Ok(bytes) = serde_cbor::to_vec(call) else {
+            return;
+        };
+        let deps = call.dfut_deps_mut().into_iter().map(DFutData::share);
+        let entry = LineageEntry {
+            node,
+            call: bytes.into_boxed_slice(),
+            deps: deps.collect(),
+            retries,
+        };
+        let old = self.entries.lock().unwrap().insert(id, entry);
+        debug_assert!(old.is_none(), "{id} recorded twice");
+    }
+
+    /// Notes that `id` now runs on `node` without using up a retry.
+    pub fn moved(&self, id: DFutId, node: NodeId) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(&id) {
+            entry.node = node;
+        }
+        self.changed.notify_waiters();
+    }
+
+    /// Forgets `id`, returning the futures its call was passed.
+    pub fn remove(&self, id: DFutId) -> Vec<DFutData> {
+        let entry = self.entries.lock().unwrap().remove(&id);
+        self.changed.notify_waiters();
+        entry.map_or_else(Vec::new, |entry| entry.deps)
+    }
+
+    pub fn location(&self, id: DFutId) -> Option<NodeId> {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map(|entry| entry.node)
+    }
+
+    /// Takes every call that was running on `lost` and still has retries
+    /// left, charging each one a retry and passing it fresh copies of its
+    /// futures. Calls out of retries are forgotten, and the futures they
+    /// were passed are returned alongside.
+    pub fn take_lost<C: DFutTrait + DeserializeOwned>(
+        &self,
+        lost: NodeId,
+    ) -> (Vec<(DFutId, C)>, Vec<DFutData>) {
+        let mut entries = self.entries.lock().unwrap();
+        let mut calls = Vec::new();
+        let mut released = Vec::new();
+        entries.retain(|&id, entry| {
+            if entry.node != lost {
+                return true;
+            }
+            if entry.retries == 0 {
+                released.append(&mut entry.deps);
+                return false;
+            }
+            match serde_cbor::from_slice::<C>(&entry.call) {
+                Ok(mut call) => {
+                    entry.retries -= 1;
+                    for (dep, held) in call.dfut_deps_mut().into_iter().zip(&mut entry.deps) {
+                        let next = held.share();
+                        *dep = mem::replace(held, next);
+                    }
+                    calls.push((id, call));
+                    true
+                }
+                Err(_) => {
+                    released.append(&mut entry.deps);
+                    false
+                }
+            }
+        });
+        drop(entries);
+        self.changed.notify_waiters();
+        (calls, released)
+    }
+
+    /// Waits until `id` has been moved off `lost`, returning where it runs
+    /// now, or `None` if it will not be re-executed.
+    pub async fn locate(&self, id: DFutId, lost: NodeId) -> Option<NodeId> {
+        loop {
+            let mut changed = pin!(self.changed.notified());
+            changed.as_mut().enable();
+            match self.entries.lock().unwrap().get(&id) {
+                None => return None,
+                Some(entry) if entry.node != lost => return Some(entry.node),
+                Some(_) => {}
+            }
+            changed.await;
+        }
+    }
+}
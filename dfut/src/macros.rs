@@ -22,12 +22,15 @@ macro_rules! create_struct {
         impl<$($arg: $crate::macros::support::MaybeFutTrait<$argtype>),*> $crate::macros::support::DFutCall<dfut_impl::Call> for $name<$($arg),*> {
             type Output = $ret;
 
-            fn run(self, node: &'static $crate::Node<dfut_impl::Call>) -> impl std::future::Future<Output = Self::Output> + Send + 'static {
+            fn run(self, node: &'static $crate::Node<dfut_impl::Call>) -> impl std::future::Future<Output = Result<Self::Output, $crate::macros::support::DFutError>> + Send + 'static {
                 let Self($($arg),*) = self;
                 $(let $alias = node.resources().$resource::<$amt>();)*
                 async move {
-                    (|$($arg : $argtype,)*| async move $body
-                )($($arg.retrieve(node).await,)*).await
+                    // Every argument is retrieved before giving up on any,
+                    // so the rest aren't left holding their results.
+                    $(let $arg = $arg.retrieve(node).await;)*
+                    Ok::<_, $crate::macros::support::DFutError>((|$($arg : $argtype,)*| async move $body
+                )($($arg?,)*).await)
                 }
             }
 
@@ -77,7 +80,7 @@ macro_rules! dfut_procs {
         #[allow(non_camel_case_types)]
         mod dfut_impl {
             use std::sync::Arc;
-            use $crate::macros::support::{DFutCall, Serialize, Deserialize, Node, Value, DFutId, NodeId,};
+            use $crate::macros::support::{DFutCall, DFutError, Serialize, Deserialize, Node, Value, DFutId, NodeId,};
 
             #[derive(Serialize,Deserialize)]
             pub enum Call {
@@ -87,10 +90,10 @@ macro_rules! dfut_procs {
             impl DFutCall<Self> for Call {
                 type Output = Value;
 
-                async fn run(self, node: &'static Node<Self>) -> Self::Output {
-                    match self {
-                        $(Self::$name(inner) => Arc::new(inner.run(node).await)),*
-                    }
+                async fn run(self, node: &'static Node<Self>) -> Result<Self::Output, DFutError> {
+                    Ok(match self {
+                        $(Self::$name(inner) => Arc::new(inner.run(node).await?)),*
+                    })
                 }
 
                 fn get_dfut_deps(&self) -> impl Iterator<Item = (NodeId, DFutId)> {
@@ -111,6 +114,22 @@ macro_rules! dfut_procs {
 
         impl $crate::macros::support::DFutTrait for dfut_impl::Call{
             type Resources = $crate::or_else!{$($resources)? ()};
+
+            const FINGERPRINT: u64 = $crate::macros::support::fingerprint(concat!(
+                $(stringify!($name), "(", $(stringify!($argtype), ",",)* ")->", stringify!($ret), ";",)*
+            ));
+
+            fn dfut_deps_mut(&mut self) -> Vec<&mut $crate::macros::support::DFutData> {
+                let mut deps = Vec::new();
+                match self {
+                    $(Self::$name($name($($arg),*)) => {
+                        $(if let $crate::macros::support::MaybeFut::Fut(data) = $arg {
+                            deps.push(data);
+                        })*
+                    })*
+                }
+                deps
+            }
         }
 
         $($crate::create_struct!{
@@ -131,7 +150,10 @@ macro_rules! dfut_procs {
 // }
 
 pub mod support {
-    pub use crate::dfut::{DFutCall, DFutTrait, MaybeFut, MaybeFutTrait, Resolve};
+    pub use crate::dfut::{
+        fingerprint, DFutCall, DFutData, DFutTrait, MaybeFut, MaybeFutTrait, Resolve,
+    };
+    pub use crate::error::DFutError;
     pub use crate::node::Node;
     pub use crate::types::{DFutId, NodeId, Value};
     pub use serde::{Deserialize, Serialize};
@@ -1,32 +1,362 @@
 use std::any::Any;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::future::Future;
-use std::io;
+use std::io::{self, ErrorKind};
+use std::marker::PhantomData;
 use std::net::SocketAddr;
-use std::sync::OnceLock;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
-use rand::seq::IteratorRandom;
-use rand::thread_rng;
 use serde::de::DeserializeOwned;
-use tokio::net::TcpSocket;
-use tokio::runtime::{Builder, Runtime};
+use tokio::runtime::Builder;
+use tokio::sync::watch;
 use tokio::task::{JoinHandle, JoinSet};
+use tokio::time;
+use uuid::Uuid;
 
-use crate::connection::Connection;
+use crate::cache::{self, Cache, Lookup};
+use crate::codec::Codec;
+use crate::compression::{Compression, Compressor};
+use crate::connection::{Batching, Connection, Heartbeat, Limits, NodeState, MIN_FRAME_LEN};
 use crate::dfut::{DFut, DFutCall, DFutData, DFutTrait, DFutValue};
-use crate::resource::Resources;
-use crate::store::{PendingValue, TaskStore};
-use crate::types::{DFutId, NodeId, ResourceConfig};
+use crate::error::DFutError;
+use crate::lineage::Lineage;
+use crate::protocol::{Command, Handshake, Member, PROTOCOL_VERSION};
+use crate::resource::{Admission, Resources};
+use crate::scheduler::{LocalityScheduler, Scheduler, TaskInfo};
+use crate::stats::NodeStats;
+use crate::store::{self, Object, PendingValue, TaskStore};
+use crate::transport::{Listener, Stream, TcpTransport, Transport};
+use crate::types::{DFutId, NodeId, ResourceConfig, Value};
 
 pub struct Node<CallType: DFutTrait> {
     id: NodeId,
-    addr_map: HashMap<NodeId, SocketAddr>,
+    seeds: Vec<SocketAddr>,
 
-    rt: Runtime,
-    connections: HashMap<NodeId, Connection<CallType>>,
+    runtime: RuntimeConfig,
+    transport: Box<dyn Transport>,
+    /// Every member of the cluster this node knows about, including itself.
+    /// Grows as nodes join, and shrinks as nodes that joined are lost.
+    connections: RwLock<HashMap<NodeId, Arc<Connection<CallType>>>>,
+    /// Members from the config, which are kept when lost in case they
+    /// restart.
+    configured: HashSet<NodeId>,
 
     resources: CallType::Resources,
+    /// What tasks running here have taken of this node's resources.
+    admission: Admission,
     store: TaskStore,
+    cache: Cache,
+    scheduler: Box<dyn Scheduler>,
+    lineage: Lineage,
+    max_retries: usize,
+    queue_limit: usize,
+    heartbeat: Heartbeat,
+    backoff: Backoff,
+    batching: Batching,
+    limits: Limits,
+    cluster: String,
+    codec: Codec,
+    compressor: Compressor,
+    incarnation: Uuid,
+    exit: ShutdownHandle,
+}
+
+/// Stops a running node, making [`Node::start`] return. The node tells the
+/// rest of the cluster to stop too.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    exit: Arc<watch::Sender<Option<i32>>>,
+}
+
+impl ShutdownHandle {
+    /// Stops the node with exit code `code`. Does nothing if it is already
+    /// stopping.
+    pub fn shutdown(&self, code: i32) {
+        self.exit.send_if_modified(|exit| {
+            if exit.is_some() {
+                return false;
+            }
+            *exit = Some(code);
+            true
+        });
+    }
+
+    fn is_shutdown(&self) -> bool {
+        self.exit.borrow().is_some()
+    }
+
+    async fn wait(&self) -> i32 {
+        let mut exit = self.exit.subscribe();
+        let code = exit.wait_for(Option::is_some).await.unwrap();
+        code.unwrap()
+    }
+}
+
+/// Delay between attempts to reconnect to a peer, doubling after every
+/// failed attempt up to `max`.
+#[derive(Clone, Copy)]
+struct Backoff {
+    initial: Duration,
+    max: Duration,
+}
+
+/// The runtime [`Node::start`] creates for the node.
+#[derive(Clone, Copy)]
+struct RuntimeConfig {
+    multi_threaded: bool,
+    worker_threads: Option<usize>,
+}
+
+pub struct NodeBuilder<C> {
+    id: NodeId,
+    config: HashMap<NodeId, (SocketAddr, ResourceConfig)>,
+    seeds: Vec<SocketAddr>,
+    transport: Box<dyn Transport>,
+    runtime: RuntimeConfig,
+    scheduler: Box<dyn Scheduler>,
+    max_retries: usize,
+    queue_limit: usize,
+    heartbeat: Heartbeat,
+    backoff: Backoff,
+    batching: Batching,
+    limits: Limits,
+    cluster: String,
+    codec: Codec,
+    compression: Option<(Compression, usize)>,
+    memory_limit: Option<usize>,
+    spill_dir: PathBuf,
+    _marker: PhantomData<C>,
+}
+
+impl<C: DFutTrait> NodeBuilder<C> {
+    /// Sets the placement policy for calls spawned on this node.
+    /// Defaults to [`LocalityScheduler`], which places calls taking no
+    /// futures as [`RandomScheduler`] would. Pass [`RandomScheduler`] to
+    /// ignore where arguments are held.
+    ///
+    /// [`RandomScheduler`]: crate::scheduler::RandomScheduler
+    pub fn scheduler(mut self, scheduler: impl Scheduler) -> Self {
+        self.scheduler = Box::new(scheduler);
+        self
+    }
+
+    /// Sets how many times a task spawned from this node is re-executed
+    /// after the node running it is lost. Defaults to 3.
+    pub fn max_retries(mut self, max_retries: usize) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Sets how many calls may be queued for a node, waiting for resources
+    /// there or to be sent, before [`submit`] waits for room to place
+    /// another there. Retrieving a value from a node, or sending one back,
+    /// also waits while as many commands of any kind are waiting to be
+    /// written to it. Defaults to 1024.
+    ///
+    /// The limit is soft. Commands to a node queue without bound, and only
+    /// the calls and values above wait for room. [`spawn`] can't wait, so it
+    /// places calls on nodes under the limit while there are any, and past
+    /// it once every node that can run the call is full. Calls placed again
+    /// after their node is lost go past it the same way. Commands keeping
+    /// track of futures and tasks, like those saying one was dropped or
+    /// finished, never wait. [`put`] queues nothing: the value stays here
+    /// until a node retrieves it. Only [`submit`] keeps the calls a task
+    /// makes from queueing up without bound.
+    ///
+    /// [`submit`]: crate::submit
+    /// [`spawn`]: crate::spawn
+    /// [`put`]: crate::put
+    pub fn queue_limit(mut self, calls: usize) -> Self {
+        self.queue_limit = calls;
+        self
+    }
+
+    /// Sets how often peers are pinged and how long one may stay silent
+    /// before it is declared dead and its tasks are re-executed elsewhere.
+    /// Defaults to pinging every second with a five second timeout.
+    pub fn heartbeat(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.heartbeat = Heartbeat { interval, timeout };
+        self
+    }
+
+    /// Sets how long to wait before redialling a peer whose session dropped,
+    /// doubling after every failed attempt up to `max`. Defaults to starting
+    /// at 100ms and backing off to at most five seconds.
+    pub fn reconnect_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.backoff = Backoff { initial, max };
+        self
+    }
+
+    /// Sets how many commands to a peer are written together in one frame,
+    /// and how long commands wait for others to join them before they are
+    /// written. With no wait, whatever is queued when a command is written
+    /// goes with it. Defaults to batches of up to 256 commands, with no wait.
+    pub fn batching(mut self, max_commands: usize, flush_interval: Duration) -> Self {
+        self.batching = Batching {
+            max_commands: max_commands.max(1),
+            flush_interval,
+        };
+        self
+    }
+
+    /// Caps the size of the frames sessions read, which hold a batch of
+    /// commands. Peers learn the cap in the handshake and write frames no
+    /// larger than it or their own, whichever is smaller. A peer sending a
+    /// larger frame is disconnected, and a command that can't fit in one,
+    /// like a call passed a large value, fails with [`DFutError::TooLarge`]
+    /// instead of being sent. Raised to 512 KiB if lower, and defaults to
+    /// 64 MiB.
+    pub fn max_frame_len(mut self, bytes: usize) -> Self {
+        self.limits.frame_len = bytes.clamp(MIN_FRAME_LEN, u32::MAX as usize);
+        self
+    }
+
+    /// Caps the size of a value this node retrieves from another. Larger
+    /// values are sent in chunks, and once more than this has arrived, or
+    /// the value is larger to begin with, retrieving it fails with
+    /// [`DFutError::TooLarge`]. Defaults to 1 GiB.
+    pub fn max_value_len(mut self, bytes: usize) -> Self {
+        self.limits.value_len = bytes;
+        self
+    }
+
+    /// Sets the name of the cluster this node belongs to. Nodes only accept
+    /// peers from the same cluster, so clusters sharing hosts can't connect
+    /// to each other by accident. Defaults to an empty name.
+    pub fn cluster(mut self, cluster: impl Into<String>) -> Self {
+        self.cluster = cluster.into();
+        self
+    }
+
+    /// Sets how commands and values are encoded. Every node in the cluster
+    /// must use the same codec. Defaults to [`Codec::Cbor`].
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Compresses values of at least `threshold` bytes with `compression`
+    /// before sending them to peers that compress values too. Values sent to
+    /// other peers, and smaller ones, are sent as they are. Defaults to no
+    /// compression.
+    pub fn compression(mut self, compression: Compression, threshold: usize) -> Self {
+        self.compression = Some((compression, threshold));
+        self
+    }
+
+    /// Sets addresses of existing cluster members to join through. The
+    /// node learns about the rest of the cluster from them, and they tell
+    /// everyone else about it, so its config only needs to contain itself.
+    pub fn seeds(mut self, seeds: impl IntoIterator<Item = SocketAddr>) -> Self {
+        self.seeds = seeds.into_iter().collect();
+        self
+    }
+
+    /// Sets how this node reaches its peers. Defaults to [`TcpTransport`].
+    pub fn transport(mut self, transport: impl Transport) -> Self {
+        self.transport = Box::new(transport);
+        self
+    }
+
+    /// Caps how much memory the results held on this node may take, as
+    /// measured by their serialized size. Past it, the least recently used
+    /// results are written to the spill directory and read back from there
+    /// when needed. Defaults to no limit.
+    pub fn memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    /// Sets the directory results past the memory limit are written to. Each
+    /// run of the node uses a subdirectory of its own, deleted when it stops.
+    /// Defaults to the system's temporary directory.
+    pub fn spill_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.spill_dir = dir.into();
+        self
+    }
+
+    /// Sets whether [`Node::start`] runs the node on a multi-threaded
+    /// runtime instead of a single thread, so task bodies doing synchronous
+    /// work don't hold up network IO. Has no effect on nodes run with
+    /// [`Node::serve`] or [`Node::run_main`], which use the caller's runtime.
+    /// Defaults to a single thread.
+    pub fn multi_threaded(mut self, multi_threaded: bool) -> Self {
+        self.runtime.multi_threaded = multi_threaded;
+        self
+    }
+
+    /// Sets how many worker threads the multi-threaded runtime has, and
+    /// selects it. Defaults to one per core.
+    pub fn worker_threads(mut self, worker_threads: usize) -> Self {
+        self.runtime = RuntimeConfig {
+            multi_threaded: true,
+            worker_threads: Some(worker_threads),
+        };
+        self
+    }
+
+    pub fn build(self) -> io::Result<Node<C>> {
+        let Self {
+            id,
+            config,
+            seeds,
+            transport,
+            runtime,
+            scheduler,
+            max_retries,
+            queue_limit,
+            heartbeat,
+            backoff,
+            batching,
+            limits,
+            cluster,
+            codec,
+            compression,
+            memory_limit,
+            spill_dir,
+            ..
+        } = self;
+        let resources = C::Resources::from_config(&config.get(&id).unwrap().1);
+        let admission = Admission::new(&config.get(&id).unwrap().1);
+        let configured = config.keys().copied().collect();
+        let connections = config
+            .into_iter()
+            .map(|(conn_id, (addr, resources))| {
+                (conn_id, Arc::new(Connection::new(conn_id, addr, resources)))
+            })
+            .collect();
+        let incarnation = Uuid::new_v4();
+        let spill_dir = spill_dir.join(format!("dfut-{id}-{incarnation}"));
+        Ok(Node {
+            id,
+            runtime,
+            transport,
+            seeds,
+            connections: RwLock::new(connections),
+            configured,
+            store: TaskStore::new(memory_limit, spill_dir, codec),
+            cache: Cache::new(),
+            resources,
+            admission,
+            scheduler,
+            lineage: Lineage::new(),
+            max_retries,
+            queue_limit,
+            heartbeat,
+            backoff,
+            batching,
+            limits,
+            cluster,
+            codec,
+            compressor: Compressor::new(compression),
+            incarnation,
+            exit: ShutdownHandle {
+                exit: Arc::new(watch::Sender::new(None)),
+            },
+        })
+    }
 }
 
 impl<C: DFutTrait> Node<C> {
@@ -34,139 +364,955 @@ impl<C: DFutTrait> Node<C> {
         id: NodeId,
         config: HashMap<NodeId, (SocketAddr, ResourceConfig)>,
     ) -> io::Result<Self> {
-        let resources = C::Resources::from_config(&config.get(&id).unwrap().1);
-        let mut connections = HashMap::new();
-        let mut addr_map = HashMap::new();
-        for (conn_id, (addr, resources)) in config.into_iter() {
-            connections.insert(conn_id, Connection::new(conn_id, resources));
-            addr_map.insert(conn_id, addr);
-        }
-        Ok(Self {
+        Self::builder(id, config).build()
+    }
+
+    pub fn builder(
+        id: NodeId,
+        config: HashMap<NodeId, (SocketAddr, ResourceConfig)>,
+    ) -> NodeBuilder<C> {
+        NodeBuilder {
             id,
-            rt: Builder::new_current_thread().enable_all().build()?,
-            addr_map,
-            connections,
-            store: TaskStore::new(),
-            resources,
-        })
+            config,
+            seeds: Vec::new(),
+            transport: Box::new(TcpTransport),
+            runtime: RuntimeConfig {
+                multi_threaded: false,
+                worker_threads: None,
+            },
+            scheduler: Box::new(LocalityScheduler),
+            max_retries: 3,
+            queue_limit: 1024,
+            heartbeat: Heartbeat {
+                interval: Duration::from_secs(1),
+                timeout: Duration::from_secs(5),
+            },
+            backoff: Backoff {
+                initial: Duration::from_millis(100),
+                max: Duration::from_secs(5),
+            },
+            batching: Batching {
+                max_commands: 256,
+                flush_interval: Duration::ZERO,
+            },
+            limits: Limits {
+                frame_len: 64 << 20,
+                value_len: 1 << 30,
+            },
+            cluster: String::new(),
+            codec: Codec::default(),
+            compression: None,
+            memory_limit: None,
+            spill_dir: std::env::temp_dir(),
+            _marker: PhantomData,
+        }
     }
 
-    pub fn start(self, main: Option<impl DFutCall<C, Output = ()>>) {
-        if let Err(_) = NODE.set(Box::new(self)) {
-            panic!("Attempting to start second Node");
+    /// Runs the node until the cluster shuts down and returns the exit code
+    /// it was shut down with: 0 once the driver's `main` returns, 1 if it
+    /// failed, or whatever was passed to [`shutdown`].
+    ///
+    /// The node gets a runtime of its own, so this must not be called from
+    /// within one. Several nodes can run in one process, each on its own
+    /// thread. The node is leaked, since its tasks may outlive this call.
+    pub fn start(self, main: Option<impl DFutCall<C, Output = ()>>) -> i32 {
+        let mut builder = if self.runtime.multi_threaded {
+            Builder::new_multi_thread()
+        } else {
+            Builder::new_current_thread()
+        };
+        if let Some(worker_threads) = self.runtime.worker_threads {
+            builder.worker_threads(worker_threads);
         }
-        NODE.get()
-            .unwrap()
-            .downcast_ref::<Self>()
-            .unwrap()
-            .run(main);
-    }
-
-    fn run(&'static self, main: Option<impl DFutCall<C, Output = ()>>) {
-        self.connections.get(&self.id).unwrap().start_local(self);
-        self.rt.block_on(async {
-            let (listen_task, mut connects) = self.listen_for_remotes();
-            self.resources.initialize().await;
-            let mut connect_results = Vec::new();
-            while let Some(res) = connects.join_next().await {
-                connect_results.push(res.unwrap());
-            }
-            if let Some(main) = main {
-                // connect_results
-                //     .into_iter()
-                //     .for_each(|x| x.expect("Leader failed to connect"));
-                self.connections
-                    .get(&self.id)
-                    .unwrap()
-                    .spawn(main)
-                    .ok()
-                    .unwrap()
-                    .await
-            } else {
-                listen_task.await.unwrap();
+        let rt = builder
+            .enable_all()
+            .build()
+            .expect("Failed to start runtime");
+        let main = main.map(|main| main.to_call_type());
+        rt.block_on(self.leak().run(main))
+    }
+
+    /// Like [`start`](Self::start) without a `main`, but runs the node on
+    /// the caller's runtime, which needs IO and time enabled.
+    pub async fn serve(self) -> i32 {
+        self.leak().run(None).await
+    }
+
+    /// Like [`start`](Self::start) with a `main`, but runs the node on the
+    /// caller's runtime, which needs IO and time enabled.
+    pub async fn run_main(self, main: impl DFutCall<C, Output = ()>) -> i32 {
+        self.leak().run(Some(main.to_call_type())).await
+    }
+
+    /// What the node is holding and has done so far.
+    pub fn stats(&self) -> NodeStats {
+        NodeStats {
+            store: self.store.stats(),
+            compression: self.compressor.stats(),
+            sessions: {
+                let connections = self.connections.read().unwrap();
+                let mut sessions: Vec<_> = connections.values().map(|conn| conn.stats()).collect();
+                sessions.sort_by_key(|session| session.node);
+                sessions
+            },
+        }
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.exit.clone()
+    }
+
+    fn leak(self) -> &'static Self {
+        Box::leak(Box::new(self))
+    }
+
+    async fn run(&'static self, main: Option<C>) -> i32 {
+        self.connection(self.id).start_local(self);
+        let listener = self
+            .transport
+            .bind(self.connection(self.id).addr())
+            .await
+            .expect("Failed to listen");
+        let (listen_task, mut connects) = self.listen_for_remotes(listener);
+        self.resources.initialize().await;
+        let mut connect_results = Vec::new();
+        while let Some(res) = connects.join_next().await {
+            connect_results.push(res.unwrap());
+        }
+        if let Some(main) = main {
+            // connect_results
+            //     .into_iter()
+            //     .for_each(|x| x.expect("Leader failed to connect"));
+            let id = DFutId::new_v4();
+            let local = self.connection(self.id);
+            if local.submit(id, main).is_err() {
+                unreachable!("Local session is always open");
             }
-        });
+            let main = DFut::<C, ()>::new(self, self.id, id).try_await();
+            tokio::select! {
+                res = main => match res {
+                    Ok(()) => self.exit.shutdown(0),
+                    Err(e) => {
+                        log::error!("Main task failed: {e}");
+                        self.exit.shutdown(1);
+                    }
+                },
+                _ = self.exit.wait() => {}
+            }
+        }
+        let code = self.exit.wait().await;
+        let peers = self.peers();
+        for conn in peers.iter() {
+            conn.send(Command::Shutdown { code });
+        }
+        self.drain().await;
+        self.store.remove_spilled();
+        for conn in peers.iter() {
+            conn.close().await;
+        }
+        listen_task.abort();
+        code
     }
 
-    fn listen_for_remotes(&'static self) -> (JoinHandle<()>, JoinSet<io::Result<()>>) {
-        let listen_sock = TcpSocket::new_v4().unwrap();
-        listen_sock.set_reuseport(true).unwrap();
-        let myaddr = *self.addr_map.get(&self.id).unwrap();
-        listen_sock.bind(myaddr).unwrap();
-        let listener = listen_sock.listen(self.addr_map.len() as u32).unwrap();
+    /// Gives the tasks still running here a heartbeat timeout to finish,
+    /// then cancels the rest.
+    async fn drain(&self) {
+        if time::timeout(self.heartbeat.timeout, self.store.idle())
+            .await
+            .is_err()
+        {
+            log::warn!(
+                "Shutting down with {} tasks still running",
+                self.store.running()
+            );
+            self.store.cancel();
+        }
+    }
 
+    fn listen_for_remotes(
+        &'static self,
+        mut listener: Box<dyn Listener>,
+    ) -> (JoinHandle<()>, JoinSet<io::Result<()>>) {
         let mut set = JoinSet::new();
 
-        for (&id, &addr) in self.addr_map.iter() {
-            if id != self.id {
+        let peers: Vec<_> = self
+            .connections
+            .read()
+            .unwrap()
+            .keys()
+            .copied()
+            .filter(|&id| id != self.id)
+            .collect();
+        for id in peers {
+            self.expect_session(id);
+            if id > self.id {
                 set.spawn(async move {
-                    let sock = TcpSocket::new_v4()?;
-                    sock.set_reuseport(true)?;
-                    sock.bind(myaddr)?;
-                    let stream = sock.connect(addr).await?;
-                    self.connections
-                        .get(&id)
-                        .unwrap()
-                        .start_remote(self, stream);
-                    io::Result::Ok(())
+                    let res = self.connect(id).await;
+                    tokio::spawn(self.reconnect(id));
+                    res
                 });
             }
         }
+        for &seed in self.seeds.iter() {
+            set.spawn(self.join(seed));
+        }
 
         let listen_task = tokio::spawn(async move {
             loop {
-                let (stream, new_addr) = match listener.accept().await {
+                let (mut stream, new_addr) = match listener.accept().await {
                     Ok(pair) => pair,
                     Err(_) => continue,
                 };
-                if let Some((id, _)) = self.addr_map.iter().find(|(_, &addr)| addr == new_addr) {
-                    self.connections.get(id).unwrap().start_remote(self, stream);
+                if self.exit.is_shutdown() {
+                    continue;
                 }
+                tokio::spawn(async move {
+                    match self.handshake(&mut stream, None).await {
+                        Ok(theirs) => {
+                            let id = theirs.member.id;
+                            self.add_members(vec![theirs.member.clone()]);
+                            self.start_session(id, stream, &theirs);
+                        }
+                        Err(e) => log::warn!("Rejected connection from {new_addr}: {e}"),
+                    }
+                });
             }
         });
         (listen_task, set)
     }
 
+    async fn connect(&'static self, id: NodeId) -> io::Result<()> {
+        let mut stream = self.transport.connect(self.connection(id).addr()).await?;
+        let theirs = self
+            .handshake(&mut stream, Some(id))
+            .await
+            .inspect_err(|e| {
+                // Retrying won't help, so don't let it go unnoticed.
+                if e.kind() == ErrorKind::InvalidData {
+                    log::warn!("Refused session with node {id}: {e}");
+                }
+            })?;
+        self.start_session(id, stream, &theirs);
+        Ok(())
+    }
+
+    /// Keeps dialling `seed` with backoff until it lets this node in.
+    async fn join(&'static self, seed: SocketAddr) -> io::Result<()> {
+        let mut delay = self.backoff.initial;
+        loop {
+            let res = async {
+                let mut stream = self.transport.connect(seed).await?;
+                let theirs = self.handshake(&mut stream, None).await?;
+                let id = theirs.member.id;
+                self.add_members(vec![theirs.member.clone()]);
+                self.start_session(id, stream, &theirs);
+                io::Result::Ok(())
+            };
+            match res.await {
+                Ok(()) => return Ok(()),
+                Err(e) => log::warn!("Failed to join through {seed}: {e}"),
+            }
+            time::sleep(delay).await;
+            delay = (delay * 2).min(self.backoff.max);
+        }
+    }
+
+    /// Adds the members this node didn't know about yet and tells every
+    /// peer about them.
+    pub(crate) fn add_members(&'static self, members: Vec<Member>) {
+        let mut joined = Vec::new();
+        {
+            let mut connections = self.connections.write().unwrap();
+            for member in members {
+                if connections.contains_key(&member.id) {
+                    continue;
+                }
+                let conn = Connection::new(member.id, member.addr, member.resources.clone());
+                connections.insert(member.id, Arc::new(conn));
+                joined.push(member);
+            }
+        }
+        if joined.is_empty() {
+            return;
+        }
+        for member in joined.iter() {
+            log::info!("Node {} joined at {}", member.id, member.addr);
+            self.expect_session(member.id);
+            if member.id > self.id {
+                tokio::spawn(self.reconnect(member.id));
+            }
+        }
+        for conn in self.peers() {
+            conn.send(Command::Members {
+                members: joined.clone(),
+            });
+        }
+    }
+
+    fn members(&self) -> Vec<Member> {
+        let connections = self.connections.read().unwrap();
+        connections.values().map(|conn| conn.member()).collect()
+    }
+
+    fn peers(&self) -> Vec<Arc<Connection<C>>> {
+        let connections = self.connections.read().unwrap();
+        connections
+            .values()
+            .filter(|conn| conn.id() != self.id)
+            .cloned()
+            .collect()
+    }
+
+    /// Declares `id` lost if it doesn't connect within a heartbeat timeout.
+    fn expect_session(&'static self, id: NodeId) {
+        tokio::spawn(async move {
+            time::sleep(self.heartbeat.timeout).await;
+            if self.connection(id).expire(0) {
+                self.member_lost(id);
+            }
+        });
+    }
+
+    /// Exchanges handshakes over a fresh stream, returning the one from the
+    /// other end. `expected` is the node that was dialled, if any.
+    async fn handshake(
+        &self,
+        stream: &mut Stream,
+        expected: Option<NodeId>,
+    ) -> io::Result<Handshake> {
+        let ours = Handshake {
+            version: PROTOCOL_VERSION,
+            cluster: self.cluster.clone(),
+            fingerprint: C::FINGERPRINT,
+            codec: self.codec,
+            compression: self.compressor.compression(),
+            max_frame_len: self.limits.frame_len,
+            member: self.connection(self.id).member(),
+            incarnation: self.incarnation,
+        };
+        let exchange = async {
+            ours.write(stream).await?;
+            Handshake::read(stream).await
+        };
+        let theirs = time::timeout(self.heartbeat.timeout, exchange)
+            .await
+            .map_err(|_| io::Error::new(ErrorKind::TimedOut, "handshake timed out"))??;
+        let invalid = |msg: String| Err(io::Error::new(ErrorKind::InvalidData, msg));
+        if theirs.version != PROTOCOL_VERSION {
+            return invalid(format!(
+                "peer speaks protocol version {}, not {PROTOCOL_VERSION}",
+                theirs.version
+            ));
+        }
+        if theirs.cluster != self.cluster {
+            return invalid(format!(
+                "peer belongs to cluster {:?}, not {:?}",
+                theirs.cluster, self.cluster
+            ));
+        }
+        if theirs.fingerprint != C::FINGERPRINT {
+            return invalid(format!(
+                "peer was built with different call signatures, compared by their text \
+                 (fingerprint {:016x}, not {:016x})",
+                theirs.fingerprint,
+                C::FINGERPRINT
+            ));
+        }
+        if theirs.codec != self.codec {
+            return invalid(format!(
+                "peer encodes with {:?}, not {:?}",
+                theirs.codec, self.codec
+            ));
+        }
+        let id = theirs.member.id;
+        if id == self.id || expected.is_some_and(|expected| expected != id) {
+            return invalid(format!("unexpected node id {id}"));
+        }
+        if let Some(conn) = self.connections.read().unwrap().get(&id) {
+            if conn.addr() != theirs.member.addr {
+                return invalid(format!("node id {id} is already in use at {}", conn.addr()));
+            }
+        }
+        Ok(theirs)
+    }
+
+    /// Starts a session with `id` over a stream that completed the
+    /// handshake, `theirs` being the one it sent.
+    fn start_session(&'static self, id: NodeId, stream: Stream, theirs: &Handshake) {
+        let incarnation = theirs.incarnation;
+        let conn = self.connection(id);
+        let compressed = self.compressor.compression().is_some() && theirs.compression.is_some();
+        let frame_len = self.limits.frame_len.min(theirs.max_frame_len);
+        conn.start_remote(self, stream, incarnation, compressed, frame_len);
+        if conn.restarted(incarnation) {
+            // Everything the old process was running is gone. Re-place it
+            // before the new one is eligible, so none of it lands back there
+            // under the same id.
+            log::info!("Node {id} restarted");
+            conn.lost();
+            self.node_lost(id);
+        }
+        conn.greeted(incarnation);
+        conn.send(Command::Members {
+            members: self.members(),
+        });
+    }
+
+    /// Redials `id` with exponential backoff whenever its session drops.
+    async fn reconnect(&'static self, id: NodeId) {
+        let conn = self.connection(id);
+        let mut delay = self.backoff.initial;
+        loop {
+            conn.closed().await;
+            if self.exit.is_shutdown() || !self.is_member(&conn) {
+                return;
+            }
+            match self.connect(id).await {
+                Ok(()) => delay = self.backoff.initial,
+                Err(_) => {
+                    time::sleep(delay).await;
+                    delay = (delay * 2).min(self.backoff.max);
+                }
+            }
+        }
+    }
+
     pub fn resources(&self) -> &C::Resources {
         &self.resources
     }
 
-    fn spawn<T: DFutValue>(&self, call: impl DFutCall<C, Output = T>) -> DFut<C, T> {
-        self.connections
-            .iter()
-            .filter(|(_, conn)| conn.can_execute(&call))
-            .choose(&mut thread_rng())
-            .unwrap()
-            .1
-            .spawn(call)
-            .ok()
+    pub(crate) fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub(crate) fn heartbeat(&self) -> Heartbeat {
+        self.heartbeat
+    }
+
+    pub(crate) fn queue_limit(&self) -> usize {
+        self.queue_limit
+    }
+
+    pub(crate) fn batching(&self) -> Batching {
+        self.batching
+    }
+
+    pub(crate) fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    pub(crate) fn lineage(&self) -> &Lineage {
+        &self.lineage
+    }
+
+    pub(crate) fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    pub(crate) fn compressor(&self) -> &Compressor {
+        &self.compressor
+    }
+
+    pub(crate) fn incarnation(&self) -> Uuid {
+        self.incarnation
+    }
+
+    /// The connection to `id`, or a dead one if it was a member that has
+    /// since been lost and forgotten.
+    pub(crate) fn connection(&self, id: NodeId) -> Arc<Connection<C>> {
+        match self.connections.read().unwrap().get(&id) {
+            Some(conn) => conn.clone(),
+            None => Arc::new(Connection::gone(id)),
+        }
+    }
+
+    /// Whether `conn` is still the connection to a member.
+    fn is_member(&self, conn: &Arc<Connection<C>>) -> bool {
+        let connections = self.connections.read().unwrap();
+        connections
+            .get(&conn.id())
+            .is_some_and(|member| Arc::ptr_eq(member, conn))
+    }
+
+    /// Picks a connection to run `call` through, or `None` if no node that
+    /// is still reachable can run it.
+    fn place(&self, call: &impl DFutCall<C>) -> Option<Arc<Connection<C>>> {
+        let resources: Vec<_> = call.get_resource_deps().collect();
+        let dependencies: Vec<_> = call.get_dfut_deps().collect();
+        let task = TaskInfo {
+            resources: &resources,
+            dependencies: &dependencies,
+        };
+        let eligible: Vec<_> = self
+            .connections
+            .read()
             .unwrap()
+            .values()
+            .filter(|conn| conn.can_execute(call))
+            .cloned()
+            .collect();
+        if eligible.is_empty() {
+            return None;
+        }
+        // Nodes with a full queue are only used when every node's is.
+        let open: Vec<_> = eligible
+            .iter()
+            .filter(|conn| conn.queued_calls() < self.queue_limit)
+            .cloned()
+            .collect();
+        let eligible = if open.is_empty() { eligible } else { open };
+        // Only fall back to queueing behind running tasks when no eligible
+        // node has the resources free.
+        let mut nodes: Vec<_> = eligible
+            .iter()
+            .filter(|conn| conn.has_capacity(call))
+            .map(|conn| conn.info())
+            .collect();
+        if nodes.is_empty() {
+            nodes = eligible.iter().map(|conn| conn.info()).collect();
+        }
+        let target = self.scheduler.schedule(&task, &nodes);
+        let conn = eligible
+            .into_iter()
+            .find(|conn| conn.id() == target)
+            .expect("Scheduler chose an ineligible node");
+        Some(conn)
     }
 
-    pub(crate) fn get_from_store(&self, data: DFutData) -> PendingValue {
-        self.store.get(data)
+    fn spawn<T: DFutValue>(
+        &'static self,
+        call: impl DFutCall<C, Output = T>,
+        retries: usize,
+    ) -> DFut<C, T> {
+        let id = DFutId::new_v4();
+        let mut call = call.to_call_type();
+        loop {
+            let conn = self.place(&call).expect("No node can run this call");
+            match self.dispatch(&conn, id, call, retries) {
+                Ok(()) => return DFut::new(self, conn.id(), id),
+                // The session closed after placement; pick another node.
+                Err(returned) => call = returned,
+            }
+        }
     }
 
-    pub(crate) fn retrieve<T: Clone + DeserializeOwned + 'static>(
+    /// Like `spawn`, but first waits until the node the call would be
+    /// placed on has room for it in its queue. Placement is decided again
+    /// after waiting, as another node may have freed up in the meantime.
+    async fn submit<T: DFutValue>(
+        &'static self,
+        call: impl DFutCall<C, Output = T>,
+        retries: usize,
+    ) -> DFut<C, T> {
+        let id = DFutId::new_v4();
+        let mut call = call.to_call_type();
+        loop {
+            let conn = self.place(&call).expect("No node can run this call");
+            if conn.queued_calls() >= self.queue_limit {
+                conn.room(self.queue_limit).await;
+                continue;
+            }
+            match self.dispatch(&conn, id, call, retries) {
+                Ok(()) => return DFut::new(self, conn.id(), id),
+                Err(returned) => call = returned,
+            }
+        }
+    }
+
+    /// Submits a newly spawned call through `conn`, recording it for
+    /// re-execution if it runs elsewhere.
+    fn dispatch(
         &self,
-        data: DFutData,
-    ) -> impl Future<Output = T> + Send {
-        self.connections.get(&data.node).unwrap().retrieve(data)
+        conn: &Connection<C>,
+        id: DFutId,
+        mut call: C,
+        retries: usize,
+    ) -> Result<(), C> {
+        if conn.id() != self.id {
+            self.lineage.record(id, conn.id(), &mut call, retries);
+        }
+        conn.submit(id, call).inspect_err(|_| self.unrecord(id))
+    }
+
+    /// Forgets how to re-execute `id`, dropping the futures lineage kept
+    /// for it.
+    fn unrecord(&self, id: DFutId) {
+        for data in self.lineage.remove(id) {
+            self.dropped(data);
+        }
+    }
+
+    /// Stores `value` on this node as if a task had returned it.
+    fn put<T: DFutValue>(&'static self, value: T) -> DFut<C, T> {
+        let id = DFutId::new_v4();
+        self.store
+            .put(id, async move { Ok(Arc::new(value) as Value) });
+        DFut::new(self, self.id, id)
+    }
+
+    /// Re-places a call whose node was lost, keeping its id so existing
+    /// futures can find the new result.
+    fn resubmit(&self, id: DFutId, mut call: C) {
+        loop {
+            let Some(conn) = self.place(&call) else {
+                self.unrecord(id);
+                for data in call.dfut_deps_mut() {
+                    self.dropped(data.clone());
+                }
+                return;
+            };
+            match conn.submit(id, call) {
+                Ok(()) => return self.lineage.moved(id, conn.id()),
+                Err(returned) => call = returned,
+            }
+        }
+    }
+
+    pub(crate) fn node_lost(&self, lost: NodeId) {
+        // Nothing is re-executed once this node has stopped.
+        if self.exit.is_shutdown() {
+            return;
+        }
+        let conn = self.connection(lost);
+        // Queued calls never ran, so moving them doesn't cost a retry.
+        for (id, call) in conn.reset() {
+            self.resubmit(id, call);
+        }
+        self.cache.node_lost(lost);
+        let (calls, released) = self.lineage.take_lost(lost);
+        for data in released {
+            self.dropped(data);
+        }
+        for (id, call) in calls {
+            self.resubmit(id, call);
+        }
+    }
+
+    /// Re-places the work of a member declared dead. One that joined
+    /// through a seed is forgotten, and has to join again if it comes back.
+    fn member_lost(&self, id: NodeId) {
+        self.node_lost(id);
+        if self.configured.contains(&id) {
+            return;
+        }
+        let mut connections = self.connections.write().unwrap();
+        if connections
+            .get(&id)
+            .is_some_and(|conn| conn.state() == NodeState::Dead)
+        {
+            connections.remove(&id);
+        }
+    }
+
+    /// Gives a node whose session dropped one heartbeat timeout to come
+    /// back before re-executing its tasks elsewhere. A node that stopped
+    /// answering heartbeats gets no grace period.
+    pub(crate) fn session_closed(
+        &'static self,
+        id: NodeId,
+        generation: u64,
+        unsent: Vec<Command<C>>,
+        timed_out: bool,
+    ) {
+        let conn = self.connection(id);
+        if !conn.session_closed(generation, unsent) {
+            return;
+        }
+        if timed_out {
+            conn.lost();
+            return self.member_lost(id);
+        }
+        tokio::spawn(async move {
+            time::sleep(self.heartbeat.timeout).await;
+            if conn.expire(generation) {
+                self.member_lost(id);
+            }
+        });
+    }
+
+    /// Gets a result from the store for `reader`, the node awaiting it.
+    pub(crate) fn get_from_store(&self, data: DFutData, reader: NodeId) -> PendingValue {
+        let pending = self
+            .store
+            .get(data.id, (reader != self.id).then_some(reader));
+        self.forget(data);
+        pending
+    }
+
+    /// Accounts for a future of a task run here being awaited or dropped.
+    /// Once nothing needs the result, tells the node that spawned the task
+    /// and the nodes that may have cached the result.
+    pub(crate) fn forget(&self, data: DFutData) {
+        let Some(mut readers) = self.store.release(&data) else {
+            return;
+        };
+        if !readers.contains(&data.origin) {
+            readers.push(data.origin);
+        }
+        for node in readers {
+            if node == self.id {
+                self.freed(data.id);
+            } else {
+                self.connection(node).send(Command::Freed { id: data.id });
+            }
+        }
+    }
+
+    /// Forgets a result that was freed by the node holding it.
+    pub(crate) fn freed(&self, id: DFutId) {
+        self.unrecord(id);
+        self.cache.remove(id);
+    }
+
+    /// Forgets a future dropped without being awaited, on whichever node
+    /// holds its result.
+    pub(crate) fn dropped(&self, mut data: DFutData) {
+        self.relocate(&mut data);
+        if data.node == self.id {
+            self.forget(data);
+        } else {
+            // Lost if the node is, along with the result.
+            self.connection(data.node).send(Command::Dropped { data });
+        }
+    }
+
+    /// Points `data` at where its task was last re-executed, if this node
+    /// spawned it.
+    fn relocate(&self, data: &mut DFutData) {
+        if data.origin != self.id {
+            return;
+        }
+        if let Some(node) = self.lineage.location(data.id) {
+            if node != data.node {
+                data.node = node;
+                data.incarnation = self.connection(node).incarnation();
+            }
+        }
+    }
+
+    pub(crate) async fn retrieve<T: Clone + DeserializeOwned + 'static>(
+        &'static self,
+        mut data: DFutData,
+    ) -> Result<T, DFutError> {
+        self.relocate(&mut data);
+        if data.node == self.id {
+            return self.fetch(data).await?.into_value(self.codec);
+        }
+        let fetch = match self.cache.lookup(data.id, data.node) {
+            Lookup::Shared(rx) => match cache::shared(rx).await {
+                Some(bytes) => {
+                    // This copy of the future still has to be accounted for.
+                    self.dropped(data);
+                    return store::deserialize(self.codec, &bytes);
+                }
+                // The fetch it was waiting on failed, so it may not be
+                // shared. Try again alone.
+                None => return self.fetch(data).await?.into_value(self.codec),
+            },
+            Lookup::Fetch(fetch) => fetch,
+        };
+        match self.fetch(data).await? {
+            Object::Bytes(bytes) => {
+                let bytes = Arc::from(bytes);
+                fetch.fill(Arc::clone(&bytes));
+                store::deserialize(self.codec, &bytes)
+            }
+            // Re-executed here.
+            obj => obj.into_value(self.codec),
+        }
+    }
+
+    /// Gets a result from the node holding it, following the task to where
+    /// it was re-executed if that node is lost.
+    async fn fetch(&self, mut data: DFutData) -> Result<Object, DFutError> {
+        loop {
+            let lost = data.node;
+            let conn = self.connection(lost);
+            conn.command_room(self.queue_limit).await;
+            match conn.retrieve(data.clone()).await {
+                Err(DFutError::NodeLost(node)) if node == lost => {}
+                res => return res,
+            }
+            // The task may have been re-executed by the node that
+            // spawned it.
+            let located = if data.origin == self.id {
+                self.lineage.locate(data.id, lost).await
+            } else {
+                self.connection(data.origin).locate(data.id, lost).await
+            };
+            match located {
+                Some(node) => {
+                    data.node = node;
+                    data.incarnation = self.connection(node).incarnation();
+                }
+                None => return Err(DFutError::NodeLost(lost)),
+            }
+        }
     }
 }
 
 impl<C: DFutTrait> Node<C> {
-    pub(crate) fn run_task(&'static self, id: DFutId, call: C) {
-        self.store.put(id, call.run(self))
+    pub(crate) fn run_task(
+        &'static self,
+        id: DFutId,
+        call: C,
+        on_finish: impl FnOnce() + Send + 'static,
+    ) {
+        let context = Context {
+            node: self,
+            exit: self.exit.clone(),
+        };
+        let finished = OnFinish(Some(on_finish));
+        self.store.put(
+            id,
+            CONTEXT.scope(context, async move {
+                // Dropped with the task, so it also runs if the task panics
+                // or is cancelled.
+                let _finished = finished;
+                let _reserved = self.admission.acquire(call.get_resource_deps()).await;
+                call.run(self).await
+            }),
+        )
+    }
+
+    /// Stores `error` as the result of a call that couldn't be run at all.
+    pub(crate) fn fail_task(
+        &'static self,
+        id: DFutId,
+        error: DFutError,
+        on_finish: impl FnOnce() + Send + 'static,
+    ) {
+        self.store.put(id, async move {
+            on_finish();
+            Err(error)
+        })
+    }
+
+    pub(crate) fn release(&self, node: NodeId, id: DFutId) {
+        self.connection(node).release(id);
     }
 }
 
-static NODE: OnceLock<Box<dyn Sync + Send + Any>> = OnceLock::new();
+/// Runs a closure when dropped.
+struct OnFinish<F: FnOnce()>(Option<F>);
 
-pub fn spawn<T: DFutValue, C: DFutTrait>(call: impl DFutCall<C, Output = T>) -> DFut<C, T> {
-    NODE.get()
-        .expect("Not in context")
+impl<F: FnOnce()> Drop for OnFinish<F> {
+    fn drop(&mut self) {
+        if let Some(on_finish) = self.0.take() {
+            on_finish();
+        }
+    }
+}
+
+/// The node a task is running on, for the free functions below.
+#[derive(Clone)]
+struct Context {
+    node: &'static dyn AnyNode,
+    exit: ShutdownHandle,
+}
+
+/// What the free functions need from a node, whatever calls it runs.
+trait AnyNode: Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+
+    fn stats(&self) -> NodeStats;
+}
+
+impl<C: DFutTrait> AnyNode for Node<C> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn stats(&self) -> NodeStats {
+        Node::stats(self)
+    }
+}
+
+tokio::task_local! {
+    static CONTEXT: Context;
+}
+
+fn context() -> Context {
+    CONTEXT.try_with(Context::clone).expect("Not in context")
+}
+
+fn current<C: DFutTrait>() -> &'static Node<C> {
+    context()
+        .node
+        .as_any()
         .downcast_ref::<Node<C>>()
-        .unwrap()
-        .spawn(call)
+        .expect("Node runs a different call type")
+}
+
+/// Runs `fut` in the context of the calling task's node, so it can spawn
+/// calls too. Needed for futures handed to `tokio::spawn`, which don't
+/// inherit the context.
+pub fn in_context<F: Future>(fut: F) -> impl Future<Output = F::Output> {
+    CONTEXT.scope(context(), fut)
+}
+
+/// Places `call` on a node of the cluster and returns a future for its
+/// result.
+///
+/// A call's `#[requires(...)]` resources are reserved on the node it is
+/// placed on until it finishes, and it waits there while they are taken.
+/// Each spawning node only counts the calls it placed itself, but the node
+/// running a call also holds it back until its resources are free there, so
+/// calls spawned from different nodes take turns. A task is never lent
+/// back what it reserved while it awaits a child, so a task holding all of a
+/// node's resources that spawns and awaits a child needing them there waits
+/// forever. Leave room for such children, or spawn them without
+/// `#[requires(...)]`.
+///
+/// Nodes already queueing as many calls as the [queue limit] allows are
+/// passed over. If every node that can run the call is, it is queued anyway,
+/// past the limit. Use [`submit`] to wait for room instead.
+///
+/// [queue limit]: NodeBuilder::queue_limit
+pub fn spawn<T: DFutValue, C: DFutTrait>(call: impl DFutCall<C, Output = T>) -> DFut<C, T> {
+    let node = current();
+    node.spawn(call, node.max_retries)
+}
+
+/// Like [`spawn`], but waits while the node the call is placed on already
+/// has as many calls queued as the [queue limit] allows. A driver spawning
+/// a large number of calls with it only holds as many in memory as the
+/// cluster can take, instead of all of them.
+///
+/// [queue limit]: NodeBuilder::queue_limit
+pub async fn submit<T: DFutValue, C: DFutTrait>(call: impl DFutCall<C, Output = T>) -> DFut<C, T> {
+    let node = current();
+    node.submit(call, node.max_retries).await
+}
+
+/// Stores `value` on the calling task's node and returns a future for it,
+/// which can be passed to calls anywhere in the cluster like the future of
+/// a spawned task. Calls taking it are placed near it, and it is only sent
+/// to the nodes that need it. Unlike a task's result it can't be recomputed,
+/// so it is lost if its node is.
+///
+/// The call type usually has to be named, as in
+/// `let table: DFut<dfut_impl::Call, _> = dfut::put(table);`.
+pub fn put<T: DFutValue, C: DFutTrait>(value: T) -> DFut<C, T> {
+    current().put(value)
+}
+
+/// Shuts down every node in the cluster, making [`Node::start`] return
+/// `code` on each of them.
+pub fn shutdown(code: i32) {
+    context().exit.shutdown(code)
+}
+
+/// Like [`spawn`], but re-executes the task at most `retries` times if the
+/// node running it is lost, instead of the node's default.
+pub fn spawn_with_retries<T: DFutValue, C: DFutTrait>(
+    call: impl DFutCall<C, Output = T>,
+    retries: usize,
+) -> DFut<C, T> {
+    current().spawn(call, retries)
+}
+
+/// What the node the calling task runs on is holding and has done so far.
+pub fn stats() -> NodeStats {
+    context().node.stats()
 }
@@ -1,8 +1,149 @@
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::net::SocketAddr;
+
 use serde::{Deserialize, Serialize};
-use tokio::sync::oneshot;
+use uuid::Uuid;
 
+use crate::codec::Codec;
+use crate::compression::Compression;
 use crate::dfut::DFutData;
-use crate::types::{DFutId, InstanceId};
+use crate::error::DFutError;
+use crate::transport::{length_prefix, Stream};
+use crate::types::{DFutId, InstanceId, NodeId, ResourceConfig};
+
+pub type Payload = Result<Box<[u8]>, DFutError>;
+
+/// Bumped whenever `Handshake` or `Command` change in a way older nodes
+/// can't read.
+pub const PROTOCOL_VERSION: u32 = 14;
+
+/// Largest handshake frame accepted, so a stray connection can't make the
+/// listener allocate an arbitrary amount of memory.
+const MAX_HANDSHAKE_LEN: usize = 64 * 1024;
+
+/// A node in the cluster and how to reach it.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Member {
+    pub id: NodeId,
+    /// Address the node listens on.
+    pub addr: SocketAddr,
+    pub resources: ResourceConfig,
+}
+
+/// First frame sent by both ends of every stream, before any `Command`.
+#[derive(Serialize, Deserialize)]
+pub struct Handshake {
+    pub version: u32,
+    pub cluster: String,
+    /// The `DFutTrait::FINGERPRINT` of the calls the node runs.
+    pub fingerprint: u64,
+    /// Encoding of every frame after the handshakes, and of values.
+    pub codec: Codec,
+    /// How the node compresses values, if it does. Values are only sent
+    /// compressed between nodes that both do.
+    pub compression: Option<Compression>,
+    /// Largest frame the node reads. Peers write frames no larger than
+    /// their own limit or this, whichever is smaller.
+    pub max_frame_len: usize,
+    pub member: Member,
+    /// Identifies this run of the node, so peers can tell a node that
+    /// reconnected from one that restarted.
+    pub incarnation: Uuid,
+}
+
+impl Handshake {
+    pub async fn write(&self, stream: &mut Stream) -> io::Result<()> {
+        let payload =
+            serde_cbor::to_vec(self).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        stream.writer.send(payload).await
+    }
+
+    pub async fn read(stream: &mut Stream) -> io::Result<Self> {
+        let buf = stream
+            .reader
+            .recv(MAX_HANDSHAKE_LEN)
+            .await?
+            .ok_or_else(|| io::Error::from(ErrorKind::UnexpectedEof))?;
+        serde_cbor::from_slice(&buf).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+    }
+}
+
+/// A `Command` as it is sent over a session.
+#[derive(Serialize, Deserialize)]
+pub struct Envelope<CallType> {
+    /// Numbers the commands sent to a node, starting from 1, so ones lost
+    /// with a dropped session can be sent again. Zero for heartbeats, which
+    /// are never sent again.
+    pub seq: u64,
+    /// Sequence number of the last command received from the node.
+    pub ack: u64,
+    pub cmd: Command<CallType>,
+}
+
+/// Encoded `Envelope`s gathered into one frame, each prefixed by its length,
+/// so a session writes many small commands at once. Every frame after the
+/// handshakes is a batch, even of one.
+#[derive(Default)]
+pub struct Batch {
+    frame: Vec<u8>,
+    len: usize,
+}
+
+impl Batch {
+    /// Adds `envelope` to the batch, unless its length can't be written.
+    pub fn push(&mut self, envelope: &[u8]) -> io::Result<()> {
+        let len = length_prefix(envelope.len(), "envelope")?;
+        self.frame.extend_from_slice(&len);
+        self.frame.extend_from_slice(envelope);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Whether `envelope` can join the batch without the frame growing past
+    /// `max_len`, or its length not fitting in the frame.
+    pub fn fits(&self, envelope: &[u8], max_len: usize) -> bool {
+        length_prefix(envelope.len(), "envelope").is_ok()
+            && self.frame.len() + 4 + envelope.len() <= max_len
+    }
+
+    /// Number of envelopes in the batch.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Size of the frame so far.
+    pub fn bytes(&self) -> usize {
+        self.frame.len()
+    }
+
+    /// Returns the frame, leaving the batch empty.
+    pub fn take(&mut self) -> Vec<u8> {
+        self.len = 0;
+        mem::take(&mut self.frame)
+    }
+
+    /// Splits a frame written by a batch back into its envelopes.
+    pub fn split(mut frame: &[u8]) -> io::Result<Vec<&[u8]>> {
+        let mut envelopes = Vec::new();
+        while !frame.is_empty() {
+            let truncated = || io::Error::new(ErrorKind::InvalidData, "truncated batch");
+            let (len, rest) = frame.split_first_chunk::<4>().ok_or_else(truncated)?;
+            let len = u32::from_be_bytes(*len) as usize;
+            if rest.len() < len {
+                return Err(truncated());
+            }
+            let (envelope, rest) = rest.split_at(len);
+            envelopes.push(envelope);
+            frame = rest;
+        }
+        Ok(envelopes)
+    }
+}
 
 #[derive(Serialize, Deserialize)]
 pub enum Command<CallType> {
@@ -10,13 +151,81 @@ pub enum Command<CallType> {
         id: DFutId,
         call: CallType,
     },
+    /// Takes the place of a `Call` that couldn't be encoded or decoded. The
+    /// receiver stores `error` as its result, as if it had run and failed.
+    Failed {
+        id: DFutId,
+        error: DFutError,
+    },
     Retrieve {
         data: DFutData,
-        #[serde(skip)]
-        channel: Option<oneshot::Sender<Box<[u8]>>>,
     },
+    /// Answers a `Retrieve`. Values too large for one frame are sent as
+    /// `Chunk`s first, with the rest of the value here. `compression` is
+    /// how the whole value, chunks included, was compressed.
     Completed {
         id: InstanceId,
-        payload: Box<[u8]>,
+        #[serde(with = "payload")]
+        payload: Payload,
+        compression: Option<Compression>,
+    },
+    /// Part of a value being sent in answer to a `Retrieve`.
+    Chunk {
+        id: InstanceId,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+    Finished {
+        id: DFutId,
+    },
+    /// A future of a task run by the receiver was dropped without being
+    /// awaited.
+    Dropped {
+        data: DFutData,
+    },
+    /// Nothing needs the result of a task any more. Sent to the node that
+    /// spawned it, which stops tracking it for re-execution, and to nodes
+    /// that fetched it, which drop their cached copies.
+    Freed {
+        id: DFutId,
     },
+    Locate {
+        id: DFutId,
+        lost: NodeId,
+    },
+    Located {
+        id: DFutId,
+        node: Option<NodeId>,
+    },
+    /// Members the sender knows about, sent when a session starts and
+    /// whenever it learns of new ones.
+    Members {
+        members: Vec<Member>,
+    },
+    /// Stops the receiving node, making `Node::start` return `code`.
+    Shutdown {
+        code: i32,
+    },
+    Ping,
+    Pong,
+}
+
+/// Writes the value of a `Payload` as bytes rather than a sequence of
+/// integers, which some codecs make up to twice as large, so a value that
+/// fits in a chunk also fits in the smallest frame.
+mod payload {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use serde_bytes::{ByteBuf, Bytes};
+
+    use super::Payload;
+    use crate::error::DFutError;
+
+    pub fn serialize<S: Serializer>(payload: &Payload, serializer: S) -> Result<S::Ok, S::Error> {
+        payload.as_deref().map(Bytes::new).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Payload, D::Error> {
+        let payload = Result::<ByteBuf, DFutError>::deserialize(deserializer)?;
+        Ok(payload.map(|bytes| bytes.into_vec().into_boxed_slice()))
+    }
 }
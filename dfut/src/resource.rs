@@ -1,9 +1,11 @@
+use std::collections::BTreeMap;
 use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
 use tokio::sync::mpsc::{channel, Receiver, Sender};
-use tokio::sync::{oneshot, Semaphore};
+use tokio::sync::{oneshot, OwnedSemaphorePermit, Semaphore};
 
 pub use crate::types::ResourceConfig;
 
@@ -26,10 +28,55 @@ impl Resources for () {
     fn from_config(_config: &ResourceConfig) -> Self {}
 }
 
-type Thunk = Box<dyn FnOnce() -> () + Send>;
+/// The resources of the node tasks run on, which every task takes what it
+/// requires from before it starts, whichever node spawned it.
+pub(crate) struct Admission {
+    /// Kept in name order, so tasks taking several always take them in the
+    /// same order and can't each hold what the other waits for.
+    free: BTreeMap<String, Arc<Semaphore>>,
+}
+
+impl Admission {
+    pub fn new(config: &ResourceConfig) -> Self {
+        let free = config
+            .iter()
+            .map(|(res, &amt)| (res.clone(), Arc::new(Semaphore::new(amt))))
+            .collect();
+        Self { free }
+    }
+
+    /// Waits until `reqs` are free, and holds them until the permits are
+    /// dropped. Resources the node wasn't configured with aren't waited on.
+    pub fn acquire<'a>(
+        &self,
+        reqs: impl Iterator<Item = (&'a str, usize)>,
+    ) -> impl Future<Output = Vec<OwnedSemaphorePermit>> + Send + 'static {
+        let mut wanted: BTreeMap<&str, usize> = BTreeMap::new();
+        for (res, amt) in reqs {
+            *wanted.entry(res).or_default() += amt;
+        }
+        let wanted: Vec<_> = wanted
+            .into_iter()
+            .filter_map(|(res, amt)| {
+                let sema = self.free.get(res)?.clone();
+                Some((sema, u32::try_from(amt).unwrap_or(u32::MAX)))
+            })
+            .collect();
+        async move {
+            let mut permits = Vec::with_capacity(wanted.len());
+            for (sema, amt) in wanted {
+                permits.push(sema.acquire_many_owned(amt).await.unwrap());
+            }
+            permits
+        }
+    }
+}
+
+type Thunk = Box<dyn FnOnce() + Send>;
 
 pub struct CpuResources {
     count: usize,
+    sema: Arc<Semaphore>,
     tx: Sender<Thunk>,
 }
 
@@ -42,7 +89,11 @@ impl Resources for CpuResources {
             let rx = rx.clone();
             thread::spawn(move || thread_pool_task(rx));
         }
-        Self { count, tx }
+        Self {
+            count,
+            sema: Arc::new(Semaphore::new(count)),
+            tx,
+        }
     }
 }
 
@@ -55,37 +106,134 @@ fn thread_pool_task(rx: Arc<Mutex<Receiver<Thunk>>>) {
 impl CpuResources {
     pub fn cpus<const N: usize>(&self) -> CpuHandle {
         assert!(N <= self.count);
-        CpuHandle::new(N, self.tx.clone())
+        CpuHandle::new(N, self.sema.clone(), self.tx.clone())
     }
 }
 
+/// A claim on `n` of the node's worker threads. Every handle shares the
+/// node-wide semaphore, so at most `count` cores are busy at once.
 pub struct CpuHandle {
+    n: usize,
     sema: Arc<Semaphore>,
     sender: Sender<Thunk>,
 }
 
 impl CpuHandle {
-    fn new(n: usize, tx: Sender<Thunk>) -> Self {
+    fn new(n: usize, sema: Arc<Semaphore>, tx: Sender<Thunk>) -> Self {
         Self {
-            sema: Arc::new(Semaphore::new(n)),
+            n,
+            sema,
             sender: tx,
         }
     }
 
+    async fn acquire(&self) -> OwnedSemaphorePermit {
+        self.sema
+            .clone()
+            .acquire_many_owned(self.n as u32)
+            .await
+            .unwrap()
+    }
+
+    /// Runs `f` on one worker thread while holding all `n` reserved cores.
     pub async fn run<F: FnOnce() -> T + Send + 'static, T: Send + 'static>(
         &self,
         f: F,
     ) -> impl Future<Output = T> {
-        let permit = self.sema.clone().acquire_owned().await;
+        let permit = self.acquire().await;
         let (res_tx, res_rx) = oneshot::channel();
         self.sender
             .send(Box::new(move || {
-                res_tx.send(f()).ok().unwrap();
+                let _ = res_tx.send(panic::catch_unwind(AssertUnwindSafe(f)));
                 drop(permit);
             }))
             .await
             .ok()
             .unwrap();
-        async move { res_rx.await.unwrap() }
+        async move { resume(res_rx.await.unwrap()) }
+    }
+
+    /// Runs `f(0)` through `f(n - 1)` concurrently, one per reserved core,
+    /// and collects the results in order.
+    pub async fn run_parallel<F: Fn(usize) -> T + Send + Sync + 'static, T: Send + 'static>(
+        &self,
+        f: F,
+    ) -> impl Future<Output = Vec<T>> {
+        let permit = Arc::new(self.acquire().await);
+        let f = Arc::new(f);
+        let mut results = Vec::with_capacity(self.n);
+        for i in 0..self.n {
+            let (res_tx, res_rx) = oneshot::channel();
+            let f = f.clone();
+            let permit = permit.clone();
+            self.sender
+                .send(Box::new(move || {
+                    let _ = res_tx.send(panic::catch_unwind(AssertUnwindSafe(|| f(i))));
+                    drop(permit);
+                }))
+                .await
+                .ok()
+                .unwrap();
+            results.push(res_rx);
+        }
+        async move {
+            let mut out = Vec::with_capacity(results.len());
+            for res_rx in results {
+                out.push(resume(res_rx.await.unwrap()));
+            }
+            out
+        }
+    }
+}
+
+/// Re-raises a panic caught on a worker thread in the awaiting task, where it
+/// is reported as `DFutError::Panicked`.
+fn resume<T>(res: thread::Result<T>) -> T {
+    res.unwrap_or_else(|e| panic::resume_unwind(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+
+    /// A closure that records how many tracked closures ran alongside it.
+    fn tracked(running: &Arc<AtomicUsize>, most: &Arc<AtomicUsize>) -> impl FnOnce() + Send {
+        let (running, most) = (running.clone(), most.clone());
+        move || {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            most.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(20));
+            running.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn cpu_handles_share_the_cores() {
+        let cpus = CpuResources::from_config(&HashMap::from([("cpus".to_owned(), 3)]));
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let runs: Vec<_> = (0..12)
+            .map(|_| {
+                let handle = cpus.cpus::<1>();
+                let f = tracked(&running, &most);
+                tokio::spawn(async move { handle.run(f).await.await })
+            })
+            .collect();
+        for run in runs {
+            run.await.unwrap();
+        }
+        assert_eq!(most.load(Ordering::SeqCst), 3);
+
+        // A handle holding every core runs alone.
+        most.store(0, Ordering::SeqCst);
+        let first = cpus.cpus::<3>().run(tracked(&running, &most)).await;
+        let second = cpus.cpus::<1>().run(tracked(&running, &most)).await;
+        first.await;
+        second.await;
+        assert_eq!(most.load(Ordering::SeqCst), 1);
     }
 }
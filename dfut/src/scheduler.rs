@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rand::seq::IteratorRandom;
+use rand::thread_rng;
+
+use crate::connection::NodeState;
+use crate::types::{DFutId, NodeId, ResourceConfig};
+
+/// What a scheduler knows about the call being placed.
+pub struct TaskInfo<'a> {
+    /// Resources requested through `#[requires(...)]`.
+    pub resources: &'a [(&'a str, usize)],
+    /// Location of every future passed as an argument.
+    pub dependencies: &'a [(NodeId, DFutId)],
+}
+
+/// A node that is able to run the call being placed.
+pub struct NodeInfo<'a> {
+    pub id: NodeId,
+    /// Total resources the node was configured with.
+    pub resources: &'a ResourceConfig,
+    /// Resources not reserved by tasks this node has dispatched there.
+    /// Tasks other nodes dispatched there aren't counted, so the node may
+    /// have less free than this, and a call placed there waits its turn.
+    pub available: ResourceConfig,
+    /// Whether the node is answering heartbeats. Never `Dead`.
+    pub state: NodeState,
+    /// Tasks dispatched there from this node that have not finished yet.
+    pub running: usize,
+    /// Tasks waiting on this node for resources to free up there.
+    pub queued: usize,
+}
+
+/// Placement policy used by `Node` to pick where a spawned call runs.
+///
+/// `nodes` is never empty and only contains nodes that can run the call. If
+/// any node has the resources free right now, only those nodes are offered.
+/// The returned id must be one of them.
+pub trait Scheduler: Send + Sync + 'static {
+    fn schedule(&self, task: &TaskInfo, nodes: &[NodeInfo]) -> NodeId;
+}
+
+/// Picks uniformly at random, wherever the call's arguments are held.
+#[derive(Default)]
+pub struct RandomScheduler;
+
+impl Scheduler for RandomScheduler {
+    fn schedule(&self, _task: &TaskInfo, nodes: &[NodeInfo]) -> NodeId {
+        nodes.iter().choose(&mut thread_rng()).unwrap().id
+    }
+}
+
+/// Prefers the nodes already holding the most arguments, so results don't
+/// have to be shipped across the cluster. Ties are broken randomly.
+///
+/// This is the default scheduler, as placement already preferred the nodes
+/// holding a call's arguments before schedulers could be plugged in. For a
+/// call taking no futures every node ties, so it is placed at random, as
+/// with [`RandomScheduler`].
+#[derive(Default)]
+pub struct LocalityScheduler;
+
+impl Scheduler for LocalityScheduler {
+    fn schedule(&self, task: &TaskInfo, nodes: &[NodeInfo]) -> NodeId {
+        let mut locality: HashMap<NodeId, usize> = HashMap::new();
+        for &(node, _) in task.dependencies {
+            *locality.entry(node).or_default() += 1;
+        }
+        let score = |node: &NodeInfo| locality.get(&node.id).copied().unwrap_or(0);
+        let best = nodes.iter().map(score).max().unwrap();
+        nodes
+            .iter()
+            .filter(|node| score(node) == best)
+            .choose(&mut thread_rng())
+            .unwrap()
+            .id
+    }
+}
+
+/// Cycles through the eligible nodes in id order.
+#[derive(Default)]
+pub struct RoundRobinScheduler {
+    next: AtomicUsize,
+}
+
+impl Scheduler for RoundRobinScheduler {
+    fn schedule(&self, _task: &TaskInfo, nodes: &[NodeInfo]) -> NodeId {
+        let mut ids: Vec<_> = nodes.iter().map(|node| node.id).collect();
+        ids.sort_unstable();
+        ids[self.next.fetch_add(1, Ordering::Relaxed) % ids.len()]
+    }
+}
+
+/// Picks the node with the fewest running and queued tasks.
+#[derive(Default)]
+pub struct LeastLoadedScheduler;
+
+impl Scheduler for LeastLoadedScheduler {
+    fn schedule(&self, _task: &TaskInfo, nodes: &[NodeInfo]) -> NodeId {
+        nodes
+            .iter()
+            .min_by_key(|node| node.running + node.queued)
+            .unwrap()
+            .id
+    }
+}
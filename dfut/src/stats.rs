@@ -0,0 +1,74 @@
+use std::time::Duration;
+
+use crate::types::NodeId;
+
+/// A snapshot of what a node is holding and has done, from [`stats`].
+///
+/// [`stats`]: crate::stats()
+#[derive(Clone, Debug, Default)]
+pub struct NodeStats {
+    pub store: StoreStats,
+    pub compression: CompressionStats,
+    /// One for every node this node knows about, itself included, by id.
+    pub sessions: Vec<SessionStats>,
+}
+
+/// Results held in a node's object store.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StoreStats {
+    /// Tasks run here, or about to be, that still have futures not yet
+    /// awaited or dropped, whether their result is in memory, spilled or
+    /// not produced yet. A task stops counting once every future for it is
+    /// accounted for, even while it is still running.
+    pub entries: usize,
+    /// Serialized size of the results held in memory. Only counted when the
+    /// node has a memory limit.
+    pub memory_used: usize,
+    /// Results written to the spill directory so far, and their total size.
+    pub spilled: u64,
+    pub spilled_bytes: u64,
+    /// Times a spilled result was read back, and the total size read.
+    pub restored: u64,
+    pub restored_bytes: u64,
+}
+
+/// Values a node compressed to send to its peers, and ones it decompressed
+/// after getting them.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CompressionStats {
+    /// Values large enough to compress, their total size before and after,
+    /// and the time spent compressing them. Values compressing didn't make
+    /// smaller count as sent unchanged.
+    pub compressed: u64,
+    pub original_bytes: u64,
+    pub compressed_bytes: u64,
+    pub compress_time: Duration,
+    /// Compressed values received, and the time spent decompressing them.
+    pub decompressed: u64,
+    pub decompress_time: Duration,
+}
+
+impl CompressionStats {
+    /// How many times smaller compression made the values sent, or 1 if
+    /// nothing was compressed yet.
+    pub fn ratio(&self) -> f64 {
+        if self.compressed_bytes == 0 {
+            return 1.0;
+        }
+        self.original_bytes as f64 / self.compressed_bytes as f64
+    }
+}
+
+/// What this node has queued for another node.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SessionStats {
+    pub node: NodeId,
+    /// Calls placed on the node that haven't been sent yet, whether waiting
+    /// for resources there, behind other commands, or for it to reconnect.
+    pub queued_calls: usize,
+    /// Commands of any kind waiting to be sent.
+    pub queued_commands: usize,
+    /// Commands sent but not acknowledged yet, kept to be sent again if the
+    /// session drops.
+    pub unacked: usize,
+}
@@ -1,107 +1,466 @@
-use std::collections::HashMap;
+use serde::de::DeserializeOwned;
+use std::any::TypeId;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
 use std::future::Future;
-use std::sync::Mutex;
-use tokio::sync::broadcast::{self, Receiver, Sender};
+use std::io::{self, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
 
+use crate::codec::Codec;
 use crate::dfut::DFutData;
-use crate::types::{DFutId, InstanceId, Value};
+use crate::error::DFutError;
+use crate::stats::StoreStats;
+use crate::types::{DFutId, InstanceId, NodeId, TaskResult, Value};
 
 pub struct TaskStore {
-    map: Mutex<HashMap<DFutId, Entry>>,
+    objects: Mutex<Objects>,
+    /// Number of tasks started on this node that haven't finished yet.
+    running: Arc<watch::Sender<usize>>,
+    /// Set to cancel every running task.
+    cancelled: watch::Sender<bool>,
+    /// Most bytes of results kept in memory before spilling, if limited.
+    memory_limit: Option<usize>,
+    /// Where results past the memory limit are written.
+    spill_dir: PathBuf,
+    codec: Codec,
+}
+
+#[derive(Default)]
+struct Objects {
+    map: HashMap<DFutId, Entry>,
+    /// Finished results held in memory, keyed by when they were last used.
+    /// Only kept when memory is limited.
+    lru: BTreeMap<u64, DFutId>,
+    clock: u64,
+    /// Bytes of results being written to disk, still counted as in memory.
+    spilling: usize,
+    stats: StoreStats,
 }
 
 impl TaskStore {
-    pub fn new() -> Self {
+    pub fn new(memory_limit: Option<usize>, spill_dir: PathBuf, codec: Codec) -> Self {
         Self {
-            map: Mutex::default(),
+            objects: Mutex::default(),
+            running: Arc::new(watch::Sender::new(0)),
+            cancelled: watch::Sender::new(false),
+            memory_limit,
+            spill_dir,
+            codec,
+        }
+    }
+
+    pub fn running(&self) -> usize {
+        *self.running.borrow()
+    }
+
+    pub fn stats(&self) -> StoreStats {
+        let objects = self.objects.lock().unwrap();
+        StoreStats {
+            entries: objects.map.len(),
+            ..objects.stats
         }
     }
 
-    pub fn put<F: Future<Output = Value> + Send + 'static>(&self, id: DFutId, task: F) {
-        let tx = self
-            .map
-            .lock()
-            .unwrap()
-            .entry(id)
-            .or_insert_with(Entry::new)
-            .get_tx();
+    /// Waits until no tasks are running on this node.
+    pub async fn idle(&self) {
+        let _ = self.running.subscribe().wait_for(|&n| n == 0).await;
+    }
+
+    /// Cancels every task still running, and any started from now on.
+    pub fn cancel(&self) {
+        self.cancelled.send_replace(true);
+    }
+
+    /// Deletes whatever is left in the spill directory.
+    pub fn remove_spilled(&self) {
+        let _ = fs::remove_dir_all(&self.spill_dir);
+    }
+
+    pub fn put<F: Future<Output = TaskResult> + Send + 'static>(
+        &'static self,
+        id: DFutId,
+        task: F,
+    ) {
+        let tx = {
+            let mut objects = self.objects.lock().unwrap();
+            let entry = objects.map.entry(id).or_insert_with(Entry::new);
+            entry.started = true;
+            let tx = entry.get_tx();
+            // Every future for the task was dropped before it got here. It
+            // still runs, but nothing keeps its result.
+            if entry.instances.is_empty() {
+                objects.remove(id);
+            }
+            tx
+        };
+        let running = self.running.clone();
+        running.send_modify(|n| *n += 1);
+        let mut cancelled = self.cancelled.subscribe();
         tokio::spawn(async move {
-            let _ = tx.send(task.await);
+            // Run the task in its own tokio task so a panic in its body is
+            // caught and stored as the result instead of unwinding here.
+            let mut handle = tokio::spawn(task);
+            let res = tokio::select! {
+                res = &mut handle => res,
+                _ = async { cancelled.wait_for(|&cancelled| cancelled).await.is_ok() } => {
+                    handle.abort();
+                    handle.await
+                }
+            };
+            let res = res.unwrap_or_else(|e| Err(e.into()));
+            self.finish(id, &tx, res).await;
+            running.send_modify(|n| *n -= 1);
         });
     }
 
-    pub fn get(&self, data: DFutData) -> PendingValue {
-        let mut map = self.map.lock().unwrap();
-        let entry = map.entry(data.id).or_insert_with(Entry::new);
-        let done = entry.update_instances(&data);
-        if !done {
-            entry.get()
-        } else {
-            map.remove(&data.id).unwrap().take()
+    /// Stores the result of a task, spilling older results if that takes
+    /// the store over its memory limit.
+    async fn finish(&self, id: DFutId, tx: &Arc<watch::Sender<Option<Stored>>>, res: TaskResult) {
+        let size = match (&res, self.memory_limit) {
+            (Ok(val), Some(_)) => serialized_len(self.codec, val),
+            _ => None,
+        };
+        tx.send_replace(Some(Stored::Done(res)));
+        let (Some(size), Some(limit)) = (size, self.memory_limit) else {
+            return;
+        };
+        let victims = {
+            let mut objects = self.objects.lock().unwrap();
+            // Nothing needs the result any more.
+            if !objects.holds(id, tx) {
+                return;
+            }
+            objects.map.get_mut(&id).unwrap().size = size;
+            objects.stats.memory_used += size;
+            objects.touch(id);
+            objects.evict(limit)
+        };
+        for victim in victims {
+            self.spill(victim).await;
+        }
+    }
+
+    async fn spill(&self, victim: Victim) {
+        let Victim {
+            id,
+            value,
+            tx,
+            size,
+        } = victim;
+        let path = self.spill_dir.join(id.to_string());
+        let dir = self.spill_dir.clone();
+        let codec = self.codec;
+        let written = tokio::task::spawn_blocking(move || {
+            let bytes = codec.encode(&value).map_err(io::Error::other)?;
+            fs::create_dir_all(&dir)?;
+            fs::write(&path, &bytes)?;
+            io::Result::Ok(SpillFile {
+                path,
+                len: bytes.len(),
+            })
+        })
+        .await
+        .unwrap_or_else(|e| Err(io::Error::other(e)));
+        let mut objects = self.objects.lock().unwrap();
+        // Freed while it was being written, which already stopped counting
+        // it.
+        if !objects.holds(id, &tx) {
+            return;
         }
+        objects.spilling -= size;
+        objects.map.get_mut(&id).unwrap().spilling = false;
+        let file = match written {
+            Ok(file) => Arc::new(file),
+            Err(e) => {
+                // Kept in memory, and not tried again.
+                log::warn!("Failed to spill result of {id}: {e}");
+                return;
+            }
+        };
+        let len = file.len as u64;
+        tx.send_replace(Some(Stored::Spilled(file)));
+        objects.map.get_mut(&id).unwrap().size = 0;
+        objects.stats.memory_used -= size;
+        objects.stats.spilled += 1;
+        objects.stats.spilled_bytes += len;
+    }
+
+    /// Gets the result of `id`, noting `reader` as a node that may cache it.
+    pub fn get(&self, id: DFutId, reader: Option<NodeId>) -> PendingValue {
+        let mut objects = self.objects.lock().unwrap();
+        let entry = objects.map.entry(id).or_insert_with(Entry::new);
+        if let Some(reader) = reader.filter(|reader| !entry.readers.contains(reader)) {
+            entry.readers.push(reader);
+        }
+        let pending = entry.get();
+        if let PendingValue::Value(Stored::Spilled(file)) = &pending {
+            objects.stats.restored += 1;
+            objects.stats.restored_bytes += file.len as u64;
+        }
+        objects.touch(id);
+        pending
+    }
+
+    /// Accounts for a future of the task being awaited or dropped. Once
+    /// none are left the result is freed, and the nodes that read it are
+    /// returned.
+    pub fn release(&self, data: &DFutData) -> Option<Vec<NodeId>> {
+        let mut objects = self.objects.lock().unwrap();
+        let entry = objects.map.entry(data.id).or_insert_with(Entry::new);
+        if !entry.update_instances(data) {
+            return None;
+        }
+        let readers = mem::take(&mut entry.readers);
+        // A task that hasn't started yet is freed by `put` instead, so it
+        // doesn't leave a result behind when it does.
+        if entry.started {
+            objects.remove(data.id);
+        }
+        Some(readers)
+    }
+}
+
+impl Objects {
+    /// Whether `id` is still stored, and still holds the result sent on
+    /// `tx`.
+    fn holds(&self, id: DFutId, tx: &Arc<watch::Sender<Option<Stored>>>) -> bool {
+        self.map
+            .get(&id)
+            .is_some_and(|entry| Arc::ptr_eq(&entry.value, tx))
+    }
+
+    /// Marks a result held in memory as just used.
+    fn touch(&mut self, id: DFutId) {
+        let Some(entry) = self.map.get_mut(&id) else {
+            return;
+        };
+        if entry.size == 0 || entry.evicted {
+            return;
+        }
+        if let Some(last_used) = entry.last_used {
+            self.lru.remove(&last_used);
+        }
+        self.clock += 1;
+        entry.last_used = Some(self.clock);
+        self.lru.insert(self.clock, id);
+    }
+
+    /// Picks the least recently used results to spill until the rest fit in
+    /// `limit`.
+    fn evict(&mut self, limit: usize) -> Vec<Victim> {
+        let mut victims = Vec::new();
+        while self.stats.memory_used - self.spilling > limit {
+            let Some((_, id)) = self.lru.pop_first() else {
+                break;
+            };
+            let entry = self.map.get_mut(&id).unwrap();
+            entry.last_used = None;
+            entry.evicted = true;
+            let value = match &*entry.value.borrow() {
+                Some(Stored::Done(Ok(value))) => value.clone(),
+                _ => continue,
+            };
+            self.spilling += entry.size;
+            entry.spilling = true;
+            victims.push(Victim {
+                id,
+                value,
+                tx: entry.value.clone(),
+                size: entry.size,
+            });
+        }
+        victims
+    }
+
+    fn remove(&mut self, id: DFutId) {
+        let Some(entry) = self.map.remove(&id) else {
+            return;
+        };
+        if let Some(last_used) = entry.last_used {
+            self.lru.remove(&last_used);
+        }
+        if entry.spilling {
+            self.spilling -= entry.size;
+        }
+        self.stats.memory_used -= entry.size;
+    }
+}
+
+/// A result on its way to the spill directory.
+struct Victim {
+    id: DFutId,
+    value: Value,
+    tx: Arc<watch::Sender<Option<Stored>>>,
+    size: usize,
+}
+
+/// Where a finished task's result is kept.
+#[derive(Clone)]
+pub enum Stored {
+    Done(TaskResult),
+    Spilled(Arc<SpillFile>),
+}
+
+/// A result written to the spill directory. The file is deleted once the
+/// result is freed and nothing is still reading it.
+pub struct SpillFile {
+    path: PathBuf,
+    len: usize,
+}
+
+impl SpillFile {
+    async fn read(self: Arc<Self>) -> Result<Box<[u8]>, DFutError> {
+        tokio::task::spawn_blocking(move || fs::read(&self.path))
+            .await?
+            .map(Vec::into_boxed_slice)
+            .map_err(|e| DFutError::Spill(e.to_string()))
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
     }
 }
 
 pub enum PendingValue {
-    Pending(Receiver<Value>),
-    Value(Value),
+    Pending(watch::Receiver<Option<Stored>>),
+    Value(Stored),
 }
 
 impl PendingValue {
-    pub async fn resolve(self) -> Value {
-        match self {
-            Self::Pending(mut rx) => rx.recv().await.unwrap(),
+    pub async fn resolve(self) -> Result<Object, DFutError> {
+        let stored = match self {
+            Self::Pending(mut rx) => match rx.wait_for(Option::is_some).await {
+                Ok(val) => val.clone().unwrap(),
+                Err(_) => return Err(DFutError::Cancelled),
+            },
             Self::Value(val) => val,
+        };
+        match stored {
+            Stored::Done(res) => res.map(Object::Value),
+            Stored::Spilled(file) => file.read().await.map(Object::Bytes),
         }
     }
 }
 
-enum FutureValue {
-    Pending(Sender<Value>, Receiver<Value>),
-    Ready(Value),
+/// A finished task's value, either as the task returned it or serialized,
+/// if it was read back from the spill directory.
+pub enum Object {
+    Value(Value),
+    Bytes(Box<[u8]>),
+}
+
+impl Object {
+    pub fn into_bytes(self, codec: Codec) -> Result<Box<[u8]>, DFutError> {
+        match self {
+            Self::Value(val) => codec
+                .encode(&val)
+                .map(Vec::into_boxed_slice)
+                .map_err(DFutError::Serialize),
+            Self::Bytes(bytes) => Ok(bytes),
+        }
+    }
+
+    pub fn into_value<T: Clone + DeserializeOwned + 'static>(
+        self,
+        codec: Codec,
+    ) -> Result<T, DFutError> {
+        match self {
+            Self::Value(val) => {
+                let val = cast(val).ok_or_else(|| {
+                    DFutError::Deserialize(format!(
+                        "stored value is not a {}",
+                        std::any::type_name::<T>()
+                    ))
+                })?;
+                Ok(Arc::unwrap_or_clone(val))
+            }
+            Self::Bytes(bytes) => deserialize(codec, &bytes),
+        }
+    }
+}
+
+pub fn deserialize<T: DeserializeOwned>(codec: Codec, bytes: &[u8]) -> Result<T, DFutError> {
+    codec.decode(bytes).map_err(DFutError::Deserialize)
+}
+
+fn cast<T: 'static>(val: Value) -> Option<Arc<T>> {
+    (val.as_ref().type_id() == TypeId::of::<T>())
+        .then(|| unsafe { Arc::from_raw(Arc::into_raw(val).cast()) })
+}
+
+/// Size of `val` once serialized, or `None` if it can't be.
+fn serialized_len(codec: Codec, val: &Value) -> Option<usize> {
+    struct Counter(usize);
+
+    impl Write for Counter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut counter = Counter(0);
+    codec.encode_into(&mut counter, val).ok()?;
+    Some(counter.0)
 }
 
 struct Entry {
-    value: FutureValue,
+    /// `None` until the task finishes. A watch rather than a broadcast
+    /// channel, so a receiver subscribed while the task is finishing on
+    /// another thread still sees the result.
+    value: Arc<watch::Sender<Option<Stored>>>,
+    /// Futures for the task not yet awaited or dropped, counted per parent
+    /// instance. See `DFutData`.
     instances: HashMap<InstanceId, i32>,
+    /// Other nodes that got the result, and may have cached it.
+    readers: Vec<NodeId>,
+    /// Whether the task was put here, rather than the entry being made by
+    /// a request that arrived first.
+    started: bool,
+    /// Serialized size of the result while it is held in memory and counted
+    /// against the memory limit.
+    size: usize,
+    /// Key in `Objects::lru` while the result may be spilled.
+    last_used: Option<u64>,
+    /// Whether the result was picked to be spilled, so it isn't picked
+    /// again while it is being written, or after writing it failed.
+    evicted: bool,
+    /// Whether the result is being written to the spill directory, and so
+    /// counted in `Objects::spilling`.
+    spilling: bool,
 }
 
 impl Entry {
     fn new() -> Self {
-        let (tx, rx) = broadcast::channel(1);
         Self {
-            value: FutureValue::Pending(tx, rx),
+            value: Arc::new(watch::Sender::new(None)),
             instances: HashMap::from([(InstanceId::nil(), 1)]),
+            readers: Vec::new(),
+            started: false,
+            size: 0,
+            last_used: None,
+            evicted: false,
+            spilling: false,
         }
     }
 
-    fn get_tx(&self) -> Sender<Value> {
-        match &self.value {
-            FutureValue::Pending(tx, _) => tx.clone(),
-            FutureValue::Ready(_) => unreachable!(),
-        }
-    }
-
-    fn get(&mut self) -> PendingValue {
-        match &mut self.value {
-            FutureValue::Pending(tx, rx) => match rx.try_recv() {
-                Ok(val) => {
-                    self.value = FutureValue::Ready(val.clone());
-                    PendingValue::Value(val)
-                }
-                Err(_) => return PendingValue::Pending(tx.subscribe()),
-            },
-            FutureValue::Ready(val) => PendingValue::Value(val.clone()),
-        }
+    fn get_tx(&self) -> Arc<watch::Sender<Option<Stored>>> {
+        self.value.clone()
     }
 
-    fn take(self) -> PendingValue {
-        match self.value {
-            FutureValue::Pending(_, mut rx) => match rx.try_recv() {
-                Ok(val) => PendingValue::Value(val),
-                Err(_) => PendingValue::Pending(rx),
-            },
-            FutureValue::Ready(val) => PendingValue::Value(val),
+    fn get(&self) -> PendingValue {
+        match &*self.value.borrow() {
+            Some(val) => PendingValue::Value(val.clone()),
+            None => PendingValue::Pending(self.value.subscribe()),
         }
     }
 
@@ -115,9 +474,46 @@ impl Entry {
         *curr_entry += data.children;
         assert!(*curr_entry >= 0);
         if *curr_entry == 0 {
-            self.instances.remove(&data.parent);
+            self.instances.remove(&data.instance_id);
         }
 
         self.instances.is_empty()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn result_freed_while_spilling() {
+        let id = DFutId::new_v4();
+        let spill_dir = std::env::temp_dir().join(format!("dfut-store-{id}"));
+        let store = Box::leak(Box::new(TaskStore::new(Some(1000), spill_dir, Codec::Cbor)));
+        store.put(id, async { Ok(Arc::new(vec![0u8; 200]) as Value) });
+        store.idle().await;
+        let victims = store.objects.lock().unwrap().evict(50);
+        assert_eq!(victims.len(), 1);
+        let data = DFutData {
+            node: 0,
+            origin: 0,
+            incarnation: None,
+            id,
+            instance_id: InstanceId::new_v4(),
+            parent: InstanceId::nil(),
+            children: 0,
+        };
+        assert!(store.release(&data).is_some());
+        {
+            let mut objects = store.objects.lock().unwrap();
+            assert_eq!(objects.spilling, 0);
+            assert!(objects.evict(50).is_empty());
+        }
+        for victim in victims {
+            store.spill(victim).await;
+        }
+        let stats = store.stats();
+        assert_eq!((stats.entries, stats.memory_used, stats.spilled), (0, 0, 0));
+        store.remove_spilled();
+    }
+}
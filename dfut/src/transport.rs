@@ -0,0 +1,516 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::time::{self, Instant};
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// How nodes reach each other. Streams carry whole frames, which have to
+/// arrive in the order they were sent, as with TCP.
+pub trait Transport: Send + Sync + 'static {
+    /// Starts accepting streams dialled to `addr`.
+    fn bind(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<Box<dyn Listener>>>;
+
+    /// Opens a stream to the node listening on `addr`.
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<Stream>>;
+}
+
+pub trait Listener: Send {
+    /// Waits for the next incoming stream and the address it came from.
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(Stream, SocketAddr)>>;
+}
+
+pub trait FrameReader: Send {
+    /// Reads the next frame, or `None` once the other end has closed the
+    /// stream. Frames longer than `max_len` are an error.
+    fn recv(&mut self, max_len: usize) -> BoxFuture<'_, io::Result<Option<Vec<u8>>>>;
+}
+
+pub trait FrameWriter: Send {
+    fn send(&mut self, frame: Vec<u8>) -> BoxFuture<'_, io::Result<()>>;
+
+    /// Tells the other end no more frames are coming, once the ones already
+    /// sent have been delivered.
+    fn close(&mut self) -> BoxFuture<'_, io::Result<()>>;
+}
+
+/// A bidirectional stream of frames between two nodes.
+pub struct Stream {
+    pub reader: Box<dyn FrameReader>,
+    pub writer: Box<dyn FrameWriter>,
+}
+
+/// Plain TCP, with every frame prefixed by its length.
+///
+/// This is the default transport.
+#[derive(Default)]
+pub struct TcpTransport;
+
+impl TcpTransport {
+    fn stream(stream: TcpStream) -> Stream {
+        let (reader, writer) = stream.into_split();
+        Stream {
+            reader: Box::new(TcpReader(reader)),
+            writer: Box::new(TcpWriter(writer)),
+        }
+    }
+}
+
+impl Transport for TcpTransport {
+    fn bind(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<Box<dyn Listener>>> {
+        Box::pin(async move {
+            let sock = if addr.is_ipv4() {
+                TcpSocket::new_v4()?
+            } else {
+                TcpSocket::new_v6()?
+            };
+            sock.set_reuseport(true)?;
+            sock.bind(addr)?;
+            let listener: Box<dyn Listener> = Box::new(TcpAcceptor(sock.listen(1024)?));
+            Ok(listener)
+        })
+    }
+
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<Stream>> {
+        Box::pin(async move { Ok(Self::stream(TcpStream::connect(addr).await?)) })
+    }
+}
+
+struct TcpAcceptor(TcpListener);
+
+impl Listener for TcpAcceptor {
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(Stream, SocketAddr)>> {
+        Box::pin(async {
+            let (stream, addr) = self.0.accept().await?;
+            Ok((TcpTransport::stream(stream), addr))
+        })
+    }
+}
+
+struct TcpReader(OwnedReadHalf);
+
+impl FrameReader for TcpReader {
+    fn recv(&mut self, max_len: usize) -> BoxFuture<'_, io::Result<Option<Vec<u8>>>> {
+        Box::pin(async move {
+            let len = match self.0.read_u32().await {
+                Ok(len) => len as usize,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            if len > max_len {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("frame of {len} bytes is too long"),
+                ));
+            }
+            let mut buf = vec![0; len];
+            self.0.read_exact(&mut buf).await?;
+            Ok(Some(buf))
+        })
+    }
+}
+
+/// The big-endian length written before `len` bytes of `what`, which
+/// fails if a `u32` can't hold it.
+pub(crate) fn length_prefix(len: usize, what: &str) -> io::Result<[u8; 4]> {
+    let len = u32::try_from(len).map_err(|_| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("{what} of {len} bytes is too long"),
+        )
+    })?;
+    Ok(len.to_be_bytes())
+}
+
+struct TcpWriter(OwnedWriteHalf);
+
+impl FrameWriter for TcpWriter {
+    fn send(&mut self, frame: Vec<u8>) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            let len = length_prefix(frame.len(), "frame")?;
+            // One write for both, so the frame isn't held back waiting for
+            // the length to be acknowledged.
+            let mut buf = Vec::with_capacity(4 + frame.len());
+            buf.extend_from_slice(&len);
+            buf.extend_from_slice(&frame);
+            self.0.write_all(&buf).await
+        })
+    }
+
+    fn close(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(self.0.shutdown())
+    }
+}
+
+/// An in-memory network for running a whole cluster in one process, with
+/// faults injected between chosen nodes.
+///
+/// Every node gets its own [`MemoryTransport`] from [`transport`]. Nodes are
+/// addressed by the address they bind, as with TCP, and links between them
+/// are named by those addresses.
+///
+/// Delays are drawn from a random number generator seeded at construction,
+/// so with tokio's time paused a run is reproducible.
+///
+/// Frames sent on one link always arrive in order, as streams promise. The
+/// only reordering is between links: with jitter, a frame from one node can
+/// arrive before a frame another node sent earlier.
+///
+/// [`transport`]: MemoryNetwork::transport
+#[derive(Clone)]
+pub struct MemoryNetwork {
+    inner: Arc<Mutex<Network>>,
+}
+
+struct Network {
+    listeners: HashMap<SocketAddr, mpsc::UnboundedSender<(Stream, SocketAddr)>>,
+    /// Open links, by the nodes on each end. Each can be cut.
+    links: Vec<(SocketAddr, SocketAddr, watch::Sender<bool>)>,
+    faults: HashMap<(SocketAddr, SocketAddr), Faults>,
+    partitions: HashSet<(SocketAddr, SocketAddr)>,
+    rng: StdRng,
+    next_port: u16,
+}
+
+/// What happens to frames sent from one node to another.
+#[derive(Clone, Copy, Default)]
+struct Faults {
+    latency: Duration,
+    jitter: Duration,
+}
+
+impl Network {
+    /// Picks when a frame sent from `from` to `to` right now arrives.
+    fn deliver_at(&mut self, from: SocketAddr, to: SocketAddr) -> Instant {
+        let faults = self.faults.get(&(from, to)).copied().unwrap_or_default();
+        let jitter = if faults.jitter.is_zero() {
+            Duration::ZERO
+        } else {
+            self.rng.gen_range(Duration::ZERO..=faults.jitter)
+        };
+        Instant::now() + faults.latency + jitter
+    }
+
+    /// Cuts every open link between `a` and `b`.
+    fn cut(&mut self, a: SocketAddr, b: SocketAddr) {
+        self.links.retain(|(from, to, cut)| {
+            if (*from, *to) == (a, b) || (*from, *to) == (b, a) {
+                cut.send_replace(true);
+                return false;
+            }
+            !cut.is_closed()
+        });
+    }
+}
+
+impl Default for MemoryNetwork {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        Self::with_seed(0)
+    }
+
+    /// Creates a network whose random delays are drawn from `seed`.
+    pub fn with_seed(seed: u64) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Network {
+                listeners: HashMap::new(),
+                links: Vec::new(),
+                faults: HashMap::new(),
+                partitions: HashSet::new(),
+                rng: StdRng::seed_from_u64(seed),
+                next_port: 49152,
+            })),
+        }
+    }
+
+    /// Returns a transport for one node to attach to this network with.
+    pub fn transport(&self) -> MemoryTransport {
+        MemoryTransport {
+            network: self.clone(),
+            local: Mutex::new(None),
+        }
+    }
+
+    /// Delays every frame sent from `from` to `to` by `latency` plus up to
+    /// `jitter` more, picked at random per frame. Frames on one link stay in
+    /// order, but overtake frames sent earlier on other links when the jitter
+    /// exceeds the time between them.
+    pub fn set_latency(
+        &self,
+        from: SocketAddr,
+        to: SocketAddr,
+        latency: Duration,
+        jitter: Duration,
+    ) {
+        let mut network = self.inner.lock().unwrap();
+        network
+            .faults
+            .insert((from, to), Faults { latency, jitter });
+    }
+
+    /// Breaks every open link between `a` and `b`. Both ends see the stream
+    /// reset and frames still in flight are lost. New links can be made
+    /// right away.
+    pub fn disconnect(&self, a: SocketAddr, b: SocketAddr) {
+        self.inner.lock().unwrap().cut(a, b);
+    }
+
+    /// Breaks every open link between `a` and `b` and refuses new ones until
+    /// [`heal`](Self::heal) is called.
+    pub fn partition(&self, a: SocketAddr, b: SocketAddr) {
+        let mut network = self.inner.lock().unwrap();
+        network.partitions.insert((a, b));
+        network.partitions.insert((b, a));
+        network.cut(a, b);
+    }
+
+    /// Lets `a` and `b` connect again after a [`partition`](Self::partition).
+    pub fn heal(&self, a: SocketAddr, b: SocketAddr) {
+        let mut network = self.inner.lock().unwrap();
+        network.partitions.remove(&(a, b));
+        network.partitions.remove(&(b, a));
+    }
+
+    fn connect(&self, from: SocketAddr, to: SocketAddr) -> io::Result<Stream> {
+        let mut network = self.inner.lock().unwrap();
+        let refused = || io::Error::new(ErrorKind::ConnectionRefused, "connection refused");
+        if network.partitions.contains(&(from, to)) {
+            return Err(refused());
+        }
+        let listener = match network.listeners.get(&to) {
+            Some(listener) if !listener.is_closed() => listener.clone(),
+            _ => return Err(refused()),
+        };
+        let (cut, _) = watch::channel(false);
+        let (ours, theirs) = (self.half(from, to, &cut), self.half(to, from, &cut));
+        let (ours, theirs) = (
+            Stream {
+                reader: Box::new(theirs.0),
+                writer: Box::new(ours.1),
+            },
+            Stream {
+                reader: Box::new(ours.0),
+                writer: Box::new(theirs.1),
+            },
+        );
+        listener.send((theirs, from)).map_err(|_| refused())?;
+        network.links.push((from, to, cut));
+        Ok(ours)
+    }
+
+    /// Creates the reading and writing ends for frames sent from `from` to
+    /// `to`.
+    fn half(
+        &self,
+        from: SocketAddr,
+        to: SocketAddr,
+        cut: &watch::Sender<bool>,
+    ) -> (MemoryReader, MemoryWriter) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let reader = MemoryReader {
+            receiver,
+            pending: VecDeque::new(),
+            cut: cut.subscribe(),
+        };
+        let writer = MemoryWriter {
+            network: self.clone(),
+            from,
+            to,
+            sender: Some(sender),
+            cut: cut.subscribe(),
+            last: Instant::now(),
+        };
+        (reader, writer)
+    }
+}
+
+/// One node's attachment to a [`MemoryNetwork`].
+pub struct MemoryTransport {
+    network: MemoryNetwork,
+    /// Address this node bound, which its outgoing links are named by.
+    local: Mutex<Option<SocketAddr>>,
+}
+
+impl MemoryTransport {
+    fn local_addr(&self) -> SocketAddr {
+        if let Some(addr) = *self.local.lock().unwrap() {
+            return addr;
+        }
+        let mut network = self.network.inner.lock().unwrap();
+        let port = network.next_port;
+        network.next_port = network.next_port.wrapping_add(1);
+        SocketAddr::from((Ipv4Addr::LOCALHOST, port))
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn bind(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<Box<dyn Listener>>> {
+        Box::pin(async move {
+            let mut network = self.network.inner.lock().unwrap();
+            if network
+                .listeners
+                .get(&addr)
+                .is_some_and(|listener| !listener.is_closed())
+            {
+                return Err(io::Error::new(
+                    ErrorKind::AddrInUse,
+                    format!("{addr} is already bound"),
+                ));
+            }
+            let (sender, receiver) = mpsc::unbounded_channel();
+            network.listeners.insert(addr, sender);
+            *self.local.lock().unwrap() = Some(addr);
+            let listener: Box<dyn Listener> = Box::new(MemoryListener(receiver));
+            Ok(listener)
+        })
+    }
+
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<Stream>> {
+        let res = self.network.connect(self.local_addr(), addr);
+        Box::pin(async move { res })
+    }
+}
+
+struct MemoryListener(mpsc::UnboundedReceiver<(Stream, SocketAddr)>);
+
+impl Listener for MemoryListener {
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(Stream, SocketAddr)>> {
+        Box::pin(async {
+            self.0
+                .recv()
+                .await
+                .ok_or_else(|| io::Error::new(ErrorKind::NotConnected, "network dropped"))
+        })
+    }
+}
+
+fn reset() -> io::Error {
+    io::Error::new(ErrorKind::ConnectionReset, "link cut")
+}
+
+struct MemoryWriter {
+    network: MemoryNetwork,
+    from: SocketAddr,
+    to: SocketAddr,
+    /// `None` once closed.
+    sender: Option<mpsc::UnboundedSender<(Instant, Vec<u8>)>>,
+    cut: watch::Receiver<bool>,
+    /// When the last frame sent is delivered. Later ones can't arrive
+    /// before it.
+    last: Instant,
+}
+
+impl FrameWriter for MemoryWriter {
+    fn send(&mut self, frame: Vec<u8>) -> BoxFuture<'_, io::Result<()>> {
+        let res = (|| {
+            if *self.cut.borrow() {
+                return Err(reset());
+            }
+            let sender = self.sender.as_ref().ok_or_else(|| {
+                io::Error::new(ErrorKind::BrokenPipe, "stream closed for writing")
+            })?;
+            let at = self
+                .network
+                .inner
+                .lock()
+                .unwrap()
+                .deliver_at(self.from, self.to)
+                .max(self.last);
+            self.last = at;
+            sender.send((at, frame)).map_err(|_| reset())
+        })();
+        Box::pin(async move { res })
+    }
+
+    fn close(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        self.sender = None;
+        Box::pin(async { Ok(()) })
+    }
+}
+
+struct MemoryReader {
+    receiver: mpsc::UnboundedReceiver<(Instant, Vec<u8>)>,
+    /// Frames sent but not yet due, in the order they were sent, which is
+    /// also the order they fall due in.
+    pending: VecDeque<(Instant, Vec<u8>)>,
+    cut: watch::Receiver<bool>,
+}
+
+impl FrameReader for MemoryReader {
+    fn recv(&mut self, max_len: usize) -> BoxFuture<'_, io::Result<Option<Vec<u8>>>> {
+        Box::pin(async move {
+            let mut open = true;
+            let mut watching = true;
+            loop {
+                if *self.cut.borrow() {
+                    return Err(reset());
+                }
+                let due = self.pending.front().map(|(at, _)| *at);
+                if due.is_some_and(|at| at <= Instant::now()) {
+                    let (_, frame) = self.pending.pop_front().unwrap();
+                    if frame.len() > max_len {
+                        return Err(io::Error::new(
+                            ErrorKind::InvalidData,
+                            format!("frame of {} bytes is too long", frame.len()),
+                        ));
+                    }
+                    return Ok(Some(frame));
+                }
+                if !open && due.is_none() {
+                    return Ok(None);
+                }
+                let sleep = async {
+                    match due {
+                        Some(at) => time::sleep_until(at).await,
+                        None => std::future::pending().await,
+                    }
+                };
+                tokio::select! {
+                    sent = self.receiver.recv(), if open => match sent {
+                        Some((at, frame)) => {
+                            self.pending.push_back((at, frame));
+                        }
+                        None => open = false,
+                    },
+                    _ = sleep => {}
+                    res = self.cut.changed(), if watching => watching = res.is_ok(),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Only a wider usize can count past a u32.
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn lengths_past_u32_are_refused() {
+        assert_eq!(length_prefix(5, "envelope").unwrap(), [0, 0, 0, 5]);
+        assert_eq!(
+            length_prefix(u32::MAX as usize, "envelope").unwrap(),
+            [0xff; 4]
+        );
+        let err = length_prefix(u32::MAX as usize + 1, "envelope").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "envelope of 4294967296 bytes is too long");
+    }
+}
@@ -4,11 +4,13 @@ use std::sync::Arc;
 use uuid::Uuid;
 
 use crate::dfut::DFutValue;
+use crate::error::DFutError;
 
 pub type NodeId = u32;
 pub type DFutId = Uuid;
 pub type InstanceId = Uuid;
 
 pub type Value = Arc<dyn DFutValue>;
+pub type TaskResult = Result<Value, DFutError>;
 
 pub type ResourceConfig = HashMap<String, usize>;
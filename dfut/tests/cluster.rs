@@ -0,0 +1,614 @@
+//! Whole clusters run in one process over a `MemoryNetwork`.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use dfut::resource::{ResourceConfig, Resources};
+use dfut::scheduler::RoundRobinScheduler;
+use dfut::transport::MemoryNetwork;
+use dfut::{dfut_procs, Codec, Compression, DFutError, Node, NodeBuilder};
+use serde::de::{self, Deserializer};
+use serde::ser::{self, Serializer};
+use serde::{Deserialize, Serialize};
+use tokio::time;
+
+/// Resources tests place calls with: each node has some number of slots.
+pub struct Slots;
+
+impl Resources for Slots {
+    fn from_config(_config: &ResourceConfig) -> Self {
+        Self
+    }
+}
+
+impl Slots {
+    pub fn slot<const N: usize>(&self) {}
+
+    /// Only configured on the nodes a test wants some calls kept to.
+    pub fn pinned<const N: usize>(&self) {}
+}
+
+/// Can be written but never read back.
+#[derive(Clone)]
+pub struct Unreadable;
+
+impl Serialize for Unreadable {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_unit()
+    }
+}
+
+impl<'de> Deserialize<'de> for Unreadable {
+    fn deserialize<D: Deserializer<'de>>(_deserializer: D) -> Result<Self, D::Error> {
+        Err(de::Error::custom("unreadable"))
+    }
+}
+
+/// Can't be written at all.
+#[derive(Clone)]
+pub struct Unwritable;
+
+impl Serialize for Unwritable {
+    fn serialize<S: Serializer>(&self, _serializer: S) -> Result<S::Ok, S::Error> {
+        Err(ser::Error::custom("unwritable"))
+    }
+}
+
+impl<'de> Deserialize<'de> for Unwritable {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        <()>::deserialize(deserializer).map(|()| Self)
+    }
+}
+
+dfut_procs! {
+#![resources(Slots)]
+
+#[requires(slot(1) as _slot)]
+async fn double(x: u64) -> u64 {
+    x * 2
+}
+
+#[requires(slot(1) as _slot)]
+async fn counted(x: u64) -> u64 {
+    RUNS.with(|runs| runs.set(runs.get() + 1));
+    x * 2
+}
+
+#[requires(slot(1) as _slot)]
+async fn slow_counted(x: u64) -> u64 {
+    RUNS.with(|runs| runs.set(runs.get() + 1));
+    time::sleep(Duration::from_secs(1)).await;
+    x * 2
+}
+
+// Counted once it has finished, unlike `slow_counted`.
+#[requires(slot(1) as _slot)]
+async fn finished_counted(x: u64) -> u64 {
+    time::sleep(Duration::from_millis(200)).await;
+    RUNS.with(|runs| runs.set(runs.get() + 1));
+    x * 2
+}
+
+#[requires(slot(1) as _slot)]
+async fn kilobyte(x: u8) -> Vec<u8> {
+    vec![x; 1024]
+}
+
+#[requires(slot(1) as _slot)]
+async fn zeros(len: usize) -> Vec<u8> {
+    vec![0; len]
+}
+
+#[requires(slot(1) as _slot)]
+async fn text(chars: usize) -> String {
+    "x".repeat(chars)
+}
+
+#[requires(slot(1) as _slot)]
+async fn count(x: Vec<u8>) -> usize {
+    RUNS.with(|runs| runs.set(runs.get() + 1));
+    x.len()
+}
+
+// Results held, spilled and restored by the node running it.
+#[requires(slot(1) as _slot)]
+async fn store_stats() -> (usize, u64, u64) {
+    let store = dfut::stats().store;
+    (store.entries, store.spilled, store.restored)
+}
+
+#[requires(slot(1) as _slot)]
+async fn slow_inc(x: u64) -> u64 {
+    time::sleep(Duration::from_secs(1)).await;
+    x + 1
+}
+
+#[requires(slot(1) as _slot)]
+async fn nested(x: u64) -> u64 {
+    dfut::spawn(double(x)).await
+}
+
+// How many calls were running at once on any node, itself included.
+#[requires(slot(1) as _slot)]
+async fn gauge() -> usize {
+    let running = RUNNING.with(|running| running.get() + 1);
+    RUNNING.set(running);
+    time::sleep(Duration::from_millis(100)).await;
+    RUNNING.set(RUNNING.get() - 1);
+    running
+}
+
+#[requires(pinned(1) as _pinned)]
+async fn fan_out() -> usize {
+    let xs: Vec<_> = (0..2).map(|_| dfut::spawn(gauge())).collect();
+    let mut most = 0;
+    for x in xs {
+        most = most.max(x.await);
+    }
+    most
+}
+
+#[requires(slot(1) as _slot)]
+async fn boom() -> u64 {
+    panic!("boom")
+}
+
+#[requires(slot(1) as _slot)]
+async fn read(_x: Unreadable) -> u64 {
+    0
+}
+
+#[requires(slot(1) as _slot)]
+async fn write(_x: Unwritable) -> u64 {
+    0
+}
+
+async fn panic_main() -> () {
+    let err = dfut::spawn(boom()).try_await().await.unwrap_err();
+    assert!(matches!(err, DFutError::Panicked(msg) if msg == "boom"));
+    // The slot the panicking task held is free again.
+    assert_eq!(dfut::spawn(double(21)).await, 42);
+}
+
+async fn drain_main() -> () {
+    let _x = dfut::spawn(finished_counted(21));
+    // Return with it running on node 1.
+    time::sleep(Duration::from_millis(50)).await;
+}
+
+async fn failing_main() -> () {
+    dfut::spawn(boom()).await;
+}
+
+async fn codec_main() -> () {
+    let err = dfut::spawn(read(Unreadable)).try_await().await.unwrap_err();
+    assert!(matches!(err, DFutError::Deserialize(_)), "{err}");
+    let err = dfut::spawn(write(Unwritable)).try_await().await.unwrap_err();
+    assert!(matches!(err, DFutError::Serialize(_)), "{err}");
+    // The session carries on.
+    assert_eq!(dfut::spawn(double(21)).await, 42);
+}
+
+async fn round_trip_main() -> () {
+    assert_eq!(dfut::spawn(double(21)).await, 42);
+    assert_eq!(dfut::spawn(count(vec![7; 1 << 10])).await, 1 << 10);
+    // Sent in chunks.
+    assert_eq!(dfut::spawn(zeros(1 << 20)).await, vec![0; 1 << 20]);
+    let err = dfut::spawn(boom()).try_await().await.unwrap_err();
+    assert!(matches!(err, DFutError::Panicked(msg) if msg == "boom"));
+}
+
+async fn compression_main() -> () {
+    assert_eq!(dfut::spawn(text(100 << 10)).await, "x".repeat(100 << 10));
+    // Compressed, then sent in chunks.
+    assert_eq!(dfut::spawn(text(1 << 20)).await, "x".repeat(1 << 20));
+    let compression = dfut::stats().compression;
+    assert_eq!(compression.decompressed, 2);
+}
+
+async fn node_loss_main() -> () {
+    // Round robin puts it on node 1.
+    let x = dfut::spawn(slow_counted(21));
+    time::sleep(Duration::from_millis(100)).await;
+    for id in [0, 2] {
+        network().partition(addr(1), addr(id));
+    }
+    assert_eq!(x.await, 42);
+    // Node 1 is cut off before it finishes, so node 2 runs it again.
+    assert_eq!(RUNS.get(), 2);
+}
+
+async fn reconnect_main() -> () {
+    let latency = Duration::from_millis(20);
+    for (from, to) in [(0, 1), (1, 0)] {
+        network().set_latency(addr(from), addr(to), latency, latency);
+    }
+    let xs: Vec<_> = (0..20).map(|x| dfut::spawn(counted(x))).collect();
+    // Drop the link with calls and results still in flight.
+    time::sleep(Duration::from_millis(30)).await;
+    network().disconnect(addr(0), addr(1));
+    for (x, fut) in (0..20).zip(xs) {
+        assert_eq!(fut.await, x * 2);
+    }
+    // What was lost was sent again, and nothing ran twice.
+    assert_eq!(RUNS.get(), 20);
+}
+
+async fn partition_main() -> () {
+    let xs: Vec<_> = (0..20).map(|x| dfut::spawn(counted(x))).collect();
+    network().partition(addr(0), addr(1));
+    // Shorter than the heartbeat timeout, so node 1 isn't given up on.
+    time::sleep(Duration::from_millis(200)).await;
+    network().heal(addr(0), addr(1));
+    for (x, fut) in (0..20).zip(xs) {
+        assert_eq!(fut.await, x * 2);
+    }
+    assert_eq!(RUNS.get(), 20);
+}
+
+async fn spill_main() -> () {
+    let xs: Vec<_> = (0..16).map(|x| dfut::spawn(kilobyte(x))).collect();
+    // Some copies of each future are awaited, the others dropped.
+    let copies: Vec<_> = xs.iter().map(Clone::clone).collect();
+    let (held, spilled, _) = dfut::spawn(store_stats()).await;
+    assert_eq!(held, 16);
+    assert!(spilled > 0, "nothing was spilled");
+    for (x, fut) in (0..16).zip(xs) {
+        assert_eq!(fut.await, vec![x; 1024]);
+    }
+    let (held, _, restored) = dfut::spawn(store_stats()).await;
+    assert_eq!(held, 16);
+    assert!(restored > 0, "nothing was restored");
+    drop(copies);
+    time::sleep(Duration::from_millis(100)).await;
+    let (held, _, _) = dfut::spawn(store_stats()).await;
+    assert_eq!(held, 0);
+}
+
+async fn limits_main() -> () {
+    let err = dfut::spawn(count(vec![0; 1 << 20])).try_await().await.unwrap_err();
+    assert!(matches!(err, DFutError::TooLarge(_)), "{err}");
+    let err = dfut::spawn(zeros(2 << 20)).try_await().await.unwrap_err();
+    assert!(matches!(err, DFutError::TooLarge(_)), "{err}");
+    // Smaller ones still get through, in chunks.
+    assert_eq!(dfut::spawn(zeros(512 << 10)).await.len(), 512 << 10);
+    assert_eq!(dfut::spawn(count(vec![0; 1 << 10])).await, 1 << 10);
+}
+
+async fn chunk_sized_main() -> () {
+    // Encodes to just under a chunk, so it is sent whole, in a frame of the
+    // smallest size allowed.
+    let chars = (256 << 10) - 8;
+    assert_eq!(dfut::spawn(text(chars)).await.len(), chars);
+}
+
+async fn frame_caps_main() -> () {
+    // Each fits the smallest frame, but not together.
+    let xs = [200 << 10, 400 << 10].map(|len| dfut::spawn(count(vec![0; len])));
+    for (len, x) in [200 << 10, 400 << 10].into_iter().zip(xs) {
+        assert_eq!(x.await, len);
+    }
+}
+
+async fn value_limit_main() -> () {
+    // Small enough to be sent whole, rather than in chunks.
+    let err = dfut::spawn(kilobyte(1)).try_await().await.unwrap_err();
+    assert!(matches!(err, DFutError::TooLarge(1000)), "{err}");
+    assert_eq!(dfut::spawn(double(21)).await, 42);
+}
+
+async fn queue_limit_main() -> () {
+    let queued = || {
+        let sessions = dfut::stats().sessions;
+        [1, 2].map(|id| sessions[id].queued_calls)
+    };
+    // Nothing is sent until this yields, so every call stays queued.
+    let mut xs: Vec<_> = (0..8).map(|x| dfut::spawn(counted(x))).collect();
+    assert_eq!(queued(), [4, 4]);
+    // Every node is full, so this one goes past the limit.
+    xs.push(dfut::spawn(counted(8)));
+    assert_eq!(queued().iter().sum::<usize>(), 9);
+    for (x, fut) in (0..9).zip(xs) {
+        assert_eq!(fut.await, x * 2);
+    }
+}
+
+async fn batch_commands_main() -> () {
+    // Past the driver's first heartbeat, which writes out what its session
+    // started with.
+    time::sleep(Duration::from_secs(15)).await;
+    let mut xs: Vec<_> = (0..3).map(|x| dfut::spawn(counted(x))).collect();
+    time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(RUNS.get(), 0);
+    // The fourth command fills the batch.
+    xs.push(dfut::spawn(counted(3)));
+    time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(RUNS.get(), 4);
+}
+
+async fn batch_bytes_main() -> () {
+    time::sleep(Duration::from_secs(15)).await;
+    let mut xs: Vec<_> = (0..2).map(|_| dfut::spawn(count(vec![0; 100 << 10]))).collect();
+    time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(RUNS.get(), 0);
+    // Takes the batch past a chunk's worth of bytes.
+    xs.push(dfut::spawn(count(vec![0; 100 << 10])));
+    time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(RUNS.get(), 3);
+}
+
+async fn nested_main() -> () {
+    assert_eq!(dfut::spawn(nested(21)).await, 42);
+}
+
+async fn admission_main() -> () {
+    // Node 2 and the driver both place calls on node 1, which has room for
+    // one at a time, without knowing about each other's.
+    let remote = dfut::spawn(fan_out());
+    let local: Vec<_> = (0..2).map(|_| dfut::spawn(gauge())).collect();
+    let mut most = remote.await;
+    for x in local {
+        most = most.max(x.await);
+    }
+    assert_eq!(most, 1);
+}
+
+async fn seed_main() -> () {
+    let nodes = || -> Vec<_> { dfut::stats().sessions.iter().map(|s| s.node).collect() };
+    // Node 1 isn't in the driver's config, and has to join through it.
+    while nodes() != [0, 1] {
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(dfut::spawn(double(21)).await, 42);
+    network().partition(addr(0), addr(1));
+    time::sleep(Duration::from_secs(1)).await;
+    // Once lost, the driver forgets it.
+    assert_eq!(nodes(), [0]);
+}
+
+async fn lineage_main() -> () {
+    // Round robin puts `a` on node 1 and `b` on node 2.
+    let a = dfut::spawn(double(21));
+    let b = dfut::spawn(slow_inc(a));
+    // Node 2 fetches `a`, using up the only future the driver had for it.
+    time::sleep(Duration::from_millis(100)).await;
+    for id in [0, 1] {
+        network().partition(addr(2), addr(id));
+    }
+    // `b` is re-executed on node 1, which must still be holding `a`.
+    assert_eq!(b.await, 43);
+}
+
+}
+
+type Call = dfut_impl::Call;
+
+thread_local! {
+    /// The network of the cluster the current test runs, whose nodes all
+    /// share the test's thread.
+    static NETWORK: MemoryNetwork = MemoryNetwork::new();
+    /// Times any node of the current test's cluster ran a counted call.
+    static RUNS: Cell<usize> = const { Cell::new(0) };
+    /// Gauge calls running right now.
+    static RUNNING: Cell<usize> = const { Cell::new(0) };
+}
+
+fn network() -> MemoryNetwork {
+    NETWORK.with(MemoryNetwork::clone)
+}
+
+fn addr(id: u32) -> SocketAddr {
+    SocketAddr::from(([10, 0, 0, id as u8], 7000))
+}
+
+/// A cluster where node `i` has `resources[i]`. Node 0 runs the driver.
+fn config(resources: Vec<ResourceConfig>) -> HashMap<u32, (SocketAddr, ResourceConfig)> {
+    (0..)
+        .zip(resources)
+        .map(|(id, resources)| (id, (addr(id), resources)))
+        .collect()
+}
+
+/// Resources of a node with `n` slots.
+fn slots(n: usize) -> ResourceConfig {
+    HashMap::from([("slot".to_owned(), n)])
+}
+
+fn builder(config: &HashMap<u32, (SocketAddr, ResourceConfig)>, id: u32) -> NodeBuilder<Call> {
+    Node::builder(id, config.clone())
+        .transport(network().transport())
+        .heartbeat(Duration::from_millis(100), Duration::from_millis(500))
+        .reconnect_backoff(Duration::from_millis(10), Duration::from_millis(100))
+}
+
+/// Starts every node but the driver, then runs `main` on node 0 and
+/// returns its exit code. Node `i` has `slots[i]` slots. `customize` adjusts
+/// each node's builder.
+async fn run(
+    slots: &[usize],
+    customize: impl Fn(u32, NodeBuilder<Call>) -> NodeBuilder<Call>,
+    main: impl dfut::macros::support::DFutCall<Call, Output = ()>,
+) -> i32 {
+    let resources = slots.iter().map(|&n| self::slots(n)).collect();
+    run_with(resources, customize, main).await
+}
+
+/// Like `run`, with node `i` having `resources[i]`.
+async fn run_with(
+    resources: Vec<ResourceConfig>,
+    customize: impl Fn(u32, NodeBuilder<Call>) -> NodeBuilder<Call>,
+    main: impl dfut::macros::support::DFutCall<Call, Output = ()>,
+) -> i32 {
+    let nodes = resources.len() as u32;
+    let config = config(resources);
+    for id in 1..nodes {
+        let node = customize(id, builder(&config, id)).build().unwrap();
+        tokio::spawn(node.serve());
+    }
+    // Let them bind before the driver dials them.
+    time::sleep(Duration::from_millis(10)).await;
+    let driver = customize(0, builder(&config, 0)).build().unwrap();
+    within(driver.run_main(main)).await
+}
+
+/// Like `run` with one follower of one slot, returning the exit codes of
+/// the driver and the follower.
+async fn run_pair(main: impl dfut::macros::support::DFutCall<Call, Output = ()>) -> (i32, i32) {
+    let config = config(vec![slots(0), slots(1)]);
+    let follower = tokio::spawn(builder(&config, 1).build().unwrap().serve());
+    time::sleep(Duration::from_millis(10)).await;
+    let driver = builder(&config, 0).build().unwrap();
+    let driver = within(driver.run_main(main)).await;
+    (driver, within(follower).await.unwrap())
+}
+
+async fn within<T>(fut: impl Future<Output = T>) -> T {
+    time::timeout(Duration::from_secs(60), fut)
+        .await
+        .expect("cluster hung")
+}
+
+#[tokio::test(start_paused = true)]
+async fn panicking_task_releases_its_resources() {
+    assert_eq!(run(&[1], |_, b| b, panic_main()).await, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn followers_drain_and_exit_with_the_driver() {
+    assert_eq!(run_pair(drain_main()).await, (0, 0));
+    // The follower finished what it was running before exiting.
+    assert_eq!(RUNS.get(), 1);
+    assert_eq!(run_pair(failing_main()).await, (1, 1));
+}
+
+#[tokio::test(start_paused = true)]
+async fn calls_that_cant_be_sent_fail() {
+    assert_eq!(run(&[0, 1], |_, b| b, codec_main()).await, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn bincode_round_trips() {
+    let codec = |_, b: NodeBuilder<Call>| b.codec(Codec::Bincode);
+    assert_eq!(run(&[0, 1], codec, round_trip_main()).await, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn postcard_round_trips() {
+    let codec = |_, b: NodeBuilder<Call>| b.codec(Codec::Postcard);
+    assert_eq!(run(&[0, 1], codec, round_trip_main()).await, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn values_are_compressed_between_nodes() {
+    for compression in [Compression::Lz4, Compression::Zstd] {
+        let compress = |_, b: NodeBuilder<Call>| b.compression(compression, 1024);
+        assert_eq!(run(&[0, 1], compress, compression_main()).await, 0);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn lineage_keeps_arguments_alive() {
+    let scheduler = |_, b: NodeBuilder<Call>| b.scheduler(RoundRobinScheduler::default());
+    assert_eq!(run(&[0, 1, 1], scheduler, lineage_main()).await, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn lost_node_tasks_run_elsewhere() {
+    let scheduler = |_, b: NodeBuilder<Call>| b.scheduler(RoundRobinScheduler::default());
+    assert_eq!(run(&[0, 1, 1], scheduler, node_loss_main()).await, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn dropped_link_replays_unacknowledged_commands() {
+    assert_eq!(run(&[0, 1], |_, b| b, reconnect_main()).await, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn short_partition_heals() {
+    assert_eq!(run(&[0, 1], |_, b| b, partition_main()).await, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn results_past_memory_limit_spill_and_free() {
+    let spill_dir = std::env::temp_dir().join("dfut-cluster-test");
+    let limit = |_, b: NodeBuilder<Call>| b.memory_limit(4096).spill_dir(&spill_dir);
+    assert_eq!(run(&[0, 1], limit, spill_main()).await, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn oversized_calls_and_values_fail() {
+    let limits = |_, b: NodeBuilder<Call>| b.max_frame_len(0).max_value_len(1 << 20);
+    assert_eq!(run(&[0, 1], limits, limits_main()).await, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn values_just_under_a_chunk_fit_the_smallest_frame() {
+    let limits = |_, b: NodeBuilder<Call>| b.max_frame_len(0);
+    assert_eq!(run(&[0, 1], limits, chunk_sized_main()).await, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn frames_fit_the_smaller_of_two_limits() {
+    let limits = |id, b: NodeBuilder<Call>| if id == 1 { b.max_frame_len(0) } else { b };
+    assert_eq!(run(&[0, 2], limits, frame_caps_main()).await, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn whole_values_over_the_limit_fail() {
+    let limits = |_, b: NodeBuilder<Call>| b.max_value_len(1000);
+    assert_eq!(run(&[0, 1], limits, value_limit_main()).await, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn spawn_passes_over_full_nodes() {
+    let limit = |_, b: NodeBuilder<Call>| b.queue_limit(4);
+    assert_eq!(run(&[0, 1, 1], limit, queue_limit_main()).await, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn nested_calls_share_a_node() {
+    assert_eq!(run(&[0, 2], |_, b| b, nested_main()).await, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn nodes_admit_calls_from_every_spawner() {
+    let pinned = HashMap::from([("pinned".to_owned(), 1)]);
+    let resources = vec![slots(0), slots(1), pinned];
+    assert_eq!(run_with(resources, |_, b| b, admission_main()).await, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn lost_nodes_that_joined_are_forgotten() {
+    let config = config(vec![slots(0), slots(1)]);
+    // Each node only knows about itself.
+    let alone = |id| HashMap::from([(id, config[&id].clone())]);
+    let node = builder(&alone(1), 1).seeds([addr(0)]).build().unwrap();
+    tokio::spawn(node.serve());
+    let driver = builder(&alone(0), 0).build().unwrap();
+    assert_eq!(within(driver.run_main(seed_main())).await, 0);
+}
+
+/// Batches until `max_commands` on the driver, whose heartbeats are the only
+/// other thing that writes them out. Node 1 pings it rarely, as pongs are
+/// batched too.
+fn batching(max_commands: usize) -> impl Fn(u32, NodeBuilder<Call>) -> NodeBuilder<Call> {
+    move |id, b| match id {
+        0 => b
+            .heartbeat(Duration::from_secs(10), Duration::from_secs(120))
+            .batching(max_commands, Duration::from_secs(3600)),
+        _ => b.heartbeat(Duration::from_secs(60), Duration::from_secs(120)),
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn batches_are_written_once_full_of_commands() {
+    assert_eq!(run(&[0, 8], batching(4), batch_commands_main()).await, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn batches_are_written_once_full_of_bytes() {
+    assert_eq!(run(&[0, 8], batching(1000), batch_bytes_main()).await, 0);
+}
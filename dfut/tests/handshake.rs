@@ -0,0 +1,127 @@
+//! Nodes that can't work together refuse each other's sessions, and say why.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Mutex, Once};
+use std::time::Duration;
+
+use dfut::macros::support::DFutTrait;
+use dfut::transport::MemoryNetwork;
+use dfut::{Codec, Node, NodeBuilder};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use tokio::time;
+
+mod driver {
+    use super::*;
+
+    dfut::dfut_procs! {
+
+    async fn double(x: u64) -> u64 {
+        x * 2
+    }
+
+    // Gives the other node time to be refused.
+    async fn wait_main() -> () {
+        time::sleep(Duration::from_secs(1)).await;
+    }
+
+    }
+
+    pub type Call = dfut_impl::Call;
+}
+
+/// The driver's calls, with `double` taking another type.
+mod changed {
+    dfut::dfut_procs! {
+
+    async fn double(x: u32) -> u32 {
+        x * 2
+    }
+
+    }
+
+    pub type Call = dfut_impl::Call;
+}
+
+/// Keeps every warning logged in this process, whichever test's node
+/// logged it.
+struct Warnings(Mutex<Vec<String>>);
+
+static WARNINGS: Warnings = Warnings(Mutex::new(Vec::new()));
+
+impl Log for Warnings {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Warn
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Whether a warning containing `text` was logged.
+fn warned(text: &str) -> bool {
+    let warnings = WARNINGS.0.lock().unwrap();
+    warnings.iter().any(|warning| warning.contains(text))
+}
+
+fn capture_warnings() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        log::set_logger(&WARNINGS).unwrap();
+        log::set_max_level(LevelFilter::Warn);
+    });
+}
+
+fn addr(id: u32) -> SocketAddr {
+    SocketAddr::from(([10, 0, 0, id as u8], 7000))
+}
+
+fn builder<C: DFutTrait>(network: &MemoryNetwork, id: u32) -> NodeBuilder<C> {
+    let config = (0..2).map(|id| (id, (addr(id), HashMap::new()))).collect();
+    Node::builder(id, config)
+        .transport(network.transport())
+        .heartbeat(Duration::from_millis(100), Duration::from_millis(500))
+}
+
+/// Runs the driver's `wait_main` next to node 1, returning its exit code.
+async fn run_next_to(
+    network: &MemoryNetwork,
+    node: impl Future<Output = i32> + Send + 'static,
+) -> i32 {
+    tokio::spawn(node);
+    time::sleep(Duration::from_millis(10)).await;
+    let driver = builder::<driver::Call>(network, 0).build().unwrap();
+    driver.run_main(driver::wait_main()).await
+}
+
+#[tokio::test(start_paused = true)]
+async fn nodes_using_another_codec_are_refused() {
+    capture_warnings();
+    let network = MemoryNetwork::new();
+    let node = builder::<driver::Call>(&network, 1)
+        .codec(Codec::Bincode)
+        .build()
+        .unwrap();
+    assert_eq!(run_next_to(&network, node.serve()).await, 0);
+    assert!(warned(
+        "Refused session with node 1: peer encodes with Bincode, not Cbor"
+    ));
+    assert!(warned("peer encodes with Cbor, not Bincode"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn nodes_with_other_calls_are_refused() {
+    capture_warnings();
+    let network = MemoryNetwork::new();
+    let node = builder::<changed::Call>(&network, 1).build().unwrap();
+    assert_eq!(run_next_to(&network, node.serve()).await, 0);
+    assert!(warned(
+        "Refused session with node 1: peer was built with different call signatures"
+    ));
+}